@@ -1,7 +1,5 @@
 #[cfg(target_os = "linux")]
 mod platform {
-    use cc;
-
     pub fn build() {
         cc::Build::new()
             .file("src/platform/linux.c")
@@ -0,0 +1,314 @@
+use std::{
+    fs,
+    io,
+    path,
+};
+
+#[cfg(feature = "checksum")]
+use crate::checksum::{self, Digest};
+use crate::{
+    error::Result,
+    labels::{self, Labels},
+    platform,
+};
+
+/// The file system operations used to exchange two paths.
+///
+/// The exchange functions of this crate only touch the file system through this trait. `Native`
+/// forwards everything to the real file system. With the `testing` feature enabled,
+/// `testing::FakeFs` provides an in-memory implementation with fault injection.
+pub trait Backend {
+    /// Atomically exchange the objects at the two paths.
+    ///
+    /// Returns `Error::NotImplemented` if atomic exchange is not available.
+    fn exchange(&self, path1: &path::Path, path2: &path::Path) -> Result<()>;
+
+    /// Check if `exchange` is available at all.
+    fn supports_exchange(&self) -> bool;
+
+    /// Look up the object at `path`, without following a final symbolic link.
+    fn lookup(&self, path: &path::Path) -> io::Result<Entry>;
+
+    /// Rename `from` to `to`, with the same semantics as `std::fs::rename`.
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+
+    /// Rename `from` to `to`, failing with `io::ErrorKind::AlreadyExists` if `to` exists.
+    fn rename_noreplace(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+
+    /// Create a new sidecar file with the given content, describing a temporary entry.
+    ///
+    /// Sidecar files help to recover from interrupted exchanges. They are optional: the default
+    /// implementation does not store anything.
+    fn write_sidecar(&self, _path: &path::Path, _content: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Remove a sidecar file created by `write_sidecar`.
+    fn remove_sidecar(&self, _path: &path::Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Undo an earlier, successful `rename(from, to)` by moving `to` back to `from`.
+    ///
+    /// This is only called while rolling back a failed non-atomic exchange.
+    fn revert(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        self.rename(to, from)
+    }
+
+    /// Compute the content digest of the object at `path`, see `Digest`.
+    ///
+    /// This is only needed to verify checksums. The default implementation fails.
+    #[cfg(feature = "checksum")]
+    fn digest(&self, path: &path::Path) -> io::Result<Digest> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot compute the digest of {} with this backend", path.display())))
+    }
+
+    /// Read the security labels of the object at `path`, without following a final symbolic link.
+    ///
+    /// This is only needed to preserve labels. The default implementation fails.
+    fn labels(&self, path: &path::Path) -> io::Result<Labels> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot read the security labels of {} with this backend", path.display())))
+    }
+
+    /// Set or remove one attribute of the security labels of the object at `path`, see `set_label`.
+    ///
+    /// This is only needed to preserve labels. The default implementation fails.
+    fn set_label(&self, path: &path::Path, name: &str, _value: Option<&[u8]>) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot set {} of {} with this backend", name, path.display())))
+    }
+}
+
+/// The type of an object in the file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    /// A type the backend does not know about.
+    Unknown,
+}
+
+impl FileType {
+    /// Check if this is a FIFO, a socket or a device node.
+    pub fn is_special(self) -> bool {
+        matches!(self, FileType::Fifo | FileType::Socket | FileType::BlockDevice | FileType::CharDevice)
+    }
+
+    /// A human readable name of this type.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileType::File => "file",
+            FileType::Dir => "directory",
+            FileType::Symlink => "symbolic link",
+            FileType::Fifo => "FIFO",
+            FileType::Socket => "socket",
+            FileType::BlockDevice => "block device",
+            FileType::CharDevice => "character device",
+            FileType::Unknown => "file of unknown type",
+        }
+    }
+}
+
+/// An object in the file system, as seen by a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The absolute path of the object, with all parent directories resolved.
+    pub path: path::PathBuf,
+    /// Device and inode number of the object, if the backend knows them.
+    pub id: Option<(u64, u64)>,
+    /// The type of the object, a symbolic link is not followed.
+    pub file_type: FileType,
+    /// Whether the object is the root of a mounted file system.
+    pub mount_point: bool,
+    /// Owner and permissions of the object, if the backend knows them.
+    pub ownership: Option<Ownership>,
+    /// Owner and permissions of the parent directory, if the backend knows them.
+    pub parent_ownership: Option<Ownership>,
+}
+
+/// The owner and the permission bits of an object in the file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ownership {
+    /// The user ID of the owner.
+    pub uid: u32,
+    /// The permission bits, including the setuid, setgid and sticky bits, like `0o1777`.
+    pub mode: u32,
+}
+
+impl Ownership {
+    /// Check if users other than the owner may write to the object, through its group or others.
+    pub fn writable_by_others(&self) -> bool {
+        self.mode & 0o022 != 0
+    }
+
+    /// Check if the sticky bit is set. In a directory, only the owner of an entry, or of the
+    /// directory, may then rename or remove it.
+    pub fn is_sticky(&self) -> bool {
+        self.mode & 0o1000 != 0
+    }
+}
+
+impl Entry {
+    /// Check if two entries refer to the same object.
+    ///
+    /// This is true for two hard links to the same inode, even though their paths differ.
+    pub fn is_same(&self, other: &Entry) -> bool {
+        match (self.id, other.id) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.path == other.path,
+        }
+    }
+
+    /// Check if this entry is a (direct or indirect) parent directory of the other entry.
+    pub fn contains(&self, other: &Entry) -> bool {
+        self.path != other.path && other.path.starts_with(&self.path)
+    }
+}
+
+/// The backend operating on the real file system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Native;
+
+impl Backend for Native {
+    fn exchange(&self, path1: &path::Path, path2: &path::Path) -> Result<()> {
+        platform::xch(path1, path2)
+    }
+
+    fn supports_exchange(&self) -> bool {
+        platform::SUPPORTS_EXCHANGE
+    }
+
+    #[cfg(feature = "checksum")]
+    fn digest(&self, path: &path::Path) -> io::Result<Digest> {
+        checksum::digest(path)
+    }
+
+    fn labels(&self, path: &path::Path) -> io::Result<Labels> {
+        labels::labels(path)
+    }
+
+    fn set_label(&self, path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+        labels::set_label(path, name, value)
+    }
+
+    fn lookup(&self, path: &path::Path) -> io::Result<Entry> {
+        let meta = fs::symlink_metadata(path)?;
+        // Only resolve the parent, rename() acts on a symbolic link, not on its target.
+        let resolved = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if parent.as_os_str().is_empty() => fs::canonicalize(".")?.join(name),
+            (Some(parent), Some(name)) => fs::canonicalize(parent)?.join(name),
+            _ => fs::canonicalize(path)?,
+        };
+        let parent_meta = match resolved.parent() {
+            Some(parent) => Some(fs::metadata(parent)?),
+            None => None,
+        };
+        let mount_point = meta.is_dir() && match parent_meta {
+            None => true,
+            Some(ref parent) => other_device(&meta, parent),
+        };
+        Ok(Entry {
+            path: resolved,
+            id: file_id(&meta),
+            file_type: file_type(&meta),
+            mount_point,
+            ownership: ownership(&meta),
+            parent_ownership: parent_meta.as_ref().and_then(ownership),
+        })
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn rename_noreplace(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        platform::rename_noreplace(from, to)
+    }
+
+    fn write_sidecar(&self, path: &path::Path, content: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(content)
+    }
+
+    fn remove_sidecar(&self, path: &path::Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+pub(crate) fn ownership(meta: &fs::Metadata) -> Option<Ownership> {
+    use std::os::unix::fs::MetadataExt;
+    Some(Ownership { uid: meta.uid(), mode: meta.mode() & 0o7777 })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn ownership(_meta: &fs::Metadata) -> Option<Ownership> {
+    None
+}
+
+/// Check if an object is on another device than its parent directory.
+///
+/// This does not detect bind mounts of a directory on the same file system.
+#[cfg(unix)]
+fn other_device(meta: &fs::Metadata, parent: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.dev() != parent.dev()
+}
+
+#[cfg(not(unix))]
+fn other_device(_meta: &fs::Metadata, _parent: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub(crate) fn file_type(meta: &fs::Metadata) -> FileType {
+    use std::os::unix::fs::FileTypeExt;
+    let t = meta.file_type();
+    if t.is_symlink() {
+        FileType::Symlink
+    } else if t.is_dir() {
+        FileType::Dir
+    } else if t.is_file() {
+        FileType::File
+    } else if t.is_fifo() {
+        FileType::Fifo
+    } else if t.is_socket() {
+        FileType::Socket
+    } else if t.is_block_device() {
+        FileType::BlockDevice
+    } else if t.is_char_device() {
+        FileType::CharDevice
+    } else {
+        FileType::Unknown
+    }
+}
+
+#[cfg(not(unix))]
+fn file_type(meta: &fs::Metadata) -> FileType {
+    let t = meta.file_type();
+    if t.is_symlink() {
+        FileType::Symlink
+    } else if t.is_dir() {
+        FileType::Dir
+    } else if t.is_file() {
+        FileType::File
+    } else {
+        FileType::Unknown
+    }
+}
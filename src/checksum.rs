@@ -0,0 +1,112 @@
+use std::{
+    ffi,
+    fmt,
+    fs,
+    io::{self, Read},
+    path,
+};
+
+use sha2::{Digest as _, Sha256};
+
+/// A SHA-256 digest of the content of a file, symbolic link or directory tree.
+///
+/// The digest covers the content of files, the targets of symbolic links, and the names and
+/// digests of all entries of directories, like a Merkle tree. Two trees with the same digest
+/// hold the same data under the same names. Metadata like permissions and timestamps is not
+/// included.
+///
+/// ```no_run
+/// let before = libxch::digest("/srv/next")?;
+/// libxch::xch("/srv/current", "/srv/next")?;
+/// assert_eq!(libxch::digest("/srv/current")?, before);
+/// # Ok::<(), libxch::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest([u8; 32]);
+
+impl Digest {
+    /// The raw bytes of the digest.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The digest of a file with the given content.
+    pub(crate) fn file<R: Read>(mut content: R) -> io::Result<Digest> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"file\0");
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match content.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => hasher.update(&buffer[..read]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Digest(hasher.finalize().into()))
+    }
+
+    /// The digest of a symbolic link to `target`.
+    pub(crate) fn symlink(target: &path::Path) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update(b"symlink\0");
+        hasher.update(os_bytes(target.as_os_str()));
+        Digest(hasher.finalize().into())
+    }
+
+    /// The digest of a directory with the given entries, in any order.
+    pub(crate) fn dir(mut entries: Vec<(Vec<u8>, Digest)>) -> Digest {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut hasher = Sha256::new();
+        hasher.update(b"dir\0");
+        for (name, digest) in entries {
+            hasher.update(&name);
+            hasher.update(b"\0");
+            hasher.update(digest.0);
+        }
+        Digest(hasher.finalize().into())
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the digest of the file, symbolic link or directory tree at `path`.
+///
+/// Symbolic links are not followed. Fails for FIFOs, sockets and device nodes, anywhere in the
+/// tree.
+pub fn digest<P: AsRef<path::Path>>(path: P) -> io::Result<Digest> {
+    let path = path.as_ref();
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if file_type.is_symlink() {
+        Ok(Digest::symlink(&fs::read_link(path)?))
+    } else if file_type.is_dir() {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            entries.push((os_bytes(&entry.file_name()), digest(entry.path())?));
+        }
+        Ok(Digest::dir(entries))
+    } else if file_type.is_file() {
+        Digest::file(fs::File::open(path)?)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot compute the digest of special file {}", path.display())))
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn os_bytes(s: &ffi::OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_bytes(s: &ffi::OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
@@ -0,0 +1,278 @@
+//! Help texts, man page and shell completions, generated from the command tables.
+
+use std::fmt::Write;
+
+use super::{Command, Flag, COMMANDS, EXIT_CODES, GLOBAL_FLAGS, NON_ATOMIC, STDIN, SWAP};
+
+const PROG_INFO: &str = concat!("xch ", env!("CARGO_PKG_VERSION"), "\n", env!("CARGO_PKG_AUTHORS"), "\nA utility to quickly swap the content of files or directories.\n");
+
+/// The help text of the program, listing all commands.
+pub fn help() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", PROG_INFO);
+    let _ = writeln!(out, "USAGE:");
+    let _ = writeln!(out, "    xch [FLAGS] [--] <PATH1> <PATH2>");
+    let _ = writeln!(out, "    xch [FLAGS] --stdin");
+    let _ = writeln!(out, "    xch <COMMAND> [FLAGS] [--] [ARGS]");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "FLAGS:");
+    write_flags(&mut out, SWAP.flags.iter().chain(GLOBAL_FLAGS));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "COMMANDS:");
+    let width = COMMANDS.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for command in COMMANDS {
+        let _ = writeln!(out, "    {:width$}    {}", command.name, command.about, width = width);
+    }
+    let _ = writeln!(out);
+    let _ = write!(out, "Run 'xch <COMMAND> --help' for the flags and arguments of a command.");
+    out
+}
+
+/// The usage text of a single command.
+pub fn usage(command: &Command) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", command.about);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "USAGE:");
+    let _ = writeln!(out, "    {}", synopsis(command));
+    if command.flags.iter().any(|f| f.long == STDIN.long) {
+        let _ = writeln!(out, "    xch {} [FLAGS] --{}", command.name, STDIN.long);
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(out, "FLAGS:");
+    write_flags(&mut out, command.flags.iter().chain(GLOBAL_FLAGS));
+    if !command.args.is_empty() {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "ARGS:");
+        let width = command.args.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, help) in command.args {
+            let _ = writeln!(out, "    {:width$}    {}", name, help, width = width);
+        }
+    }
+    out.trim_end().to_string()
+}
+
+fn synopsis(command: &Command) -> String {
+    let args: Vec<_> = command.args.iter().map(|(name, _)| *name).collect();
+    if args.is_empty() {
+        format!("xch {} [FLAGS]", command.name)
+    } else {
+        format!("xch {} [FLAGS] [--] {}", command.name, args.join(" "))
+    }
+}
+
+fn flag_name(flag: &Flag) -> String {
+    let short = flag.short.map(|c| format!("-{}, ", c)).unwrap_or_else(|| "    ".to_string());
+    match flag.value {
+        Some(value) => format!("{}--{} <{}>", short, flag.long, value),
+        None => format!("{}--{}", short, flag.long),
+    }
+}
+
+fn write_flags<'a, I: Iterator<Item = &'a Flag> + Clone>(out: &mut String, flags: I) {
+    let width = flags.clone().map(|f| flag_name(f).len()).max().unwrap_or(0);
+    for flag in flags {
+        let _ = writeln!(out, "    {:width$}    {}", flag_name(flag), flag.help, width = width);
+    }
+}
+
+/// The man page of the program, in roff format.
+pub fn man_page() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, ".TH XCH 1 \"\" \"xch {}\" \"User Commands\"", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, ".SH NAME");
+    let _ = writeln!(out, "xch \\- exchange the content of paths");
+    let _ = writeln!(out, ".SH SYNOPSIS");
+    let _ = writeln!(out, ".B xch");
+    let _ = writeln!(out, "[FLAGS] [\\-\\-] <PATH1> <PATH2>");
+    let _ = writeln!(out, ".br");
+    let _ = writeln!(out, ".B xch");
+    let _ = writeln!(out, "[FLAGS] \\-\\-stdin");
+    for command in COMMANDS {
+        let _ = writeln!(out, ".br");
+        let _ = writeln!(out, ".B xch {}", command.name);
+        let args: Vec<_> = command.args.iter().map(|(name, _)| *name).collect();
+        let _ = writeln!(out, "[FLAGS] {}", roff_escape(&args.join(" ")));
+    }
+    let _ = writeln!(out, ".SH DESCRIPTION");
+    let _ = writeln!(out, "{}", roff_escape(env!("CARGO_PKG_DESCRIPTION").trim()));
+    let _ = writeln!(out, "Without a command, \\fBxch\\fR runs the \\fBswap\\fR command.");
+    let _ = writeln!(out, ".SH FLAGS");
+    write_man_flags(&mut out, GLOBAL_FLAGS.iter());
+    let _ = writeln!(out, ".SH COMMANDS");
+    for command in COMMANDS {
+        let _ = writeln!(out, ".SS {}", command.name);
+        let _ = writeln!(out, "{}", roff_escape(command.about));
+        for (name, help) in command.args {
+            let _ = writeln!(out, ".TP");
+            let _ = writeln!(out, "\\fI{}\\fR", roff_escape(name));
+            let _ = writeln!(out, "{}", roff_escape(help));
+        }
+        write_man_flags(&mut out, command.flags.iter());
+    }
+    let _ = writeln!(out, ".SH EXIT STATUS");
+    for (code, meaning) in EXIT_CODES {
+        let _ = writeln!(out, ".TP");
+        let _ = writeln!(out, ".B {}", code);
+        let _ = writeln!(out, "{}", roff_escape(meaning));
+    }
+    out
+}
+
+fn write_man_flags<'a, I: Iterator<Item = &'a Flag>>(out: &mut String, flags: I) {
+    for flag in flags {
+        let _ = writeln!(out, ".TP");
+        let _ = writeln!(out, "\\fB{}\\fR", roff_escape(flag_name(flag).trim_start()));
+        let _ = writeln!(out, "{}", roff_escape(flag.help));
+    }
+}
+
+fn roff_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('-', "\\-")
+}
+
+/// A completion script for the given shell, or `None` if the shell is not supported.
+pub fn completions(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash_completions()),
+        "zsh" => Some(zsh_completions()),
+        "fish" => Some(fish_completions()),
+        _ => None,
+    }
+}
+
+/// All flags that take a value, without duplicates.
+fn value_flags() -> impl Iterator<Item = &'static Flag> {
+    super::all_flags().filter(|f| f.value.is_some())
+}
+
+fn long_flags<'a, I: Iterator<Item = &'a Flag>>(flags: I) -> Vec<String> {
+    flags.flat_map(|f| f.short.map(|c| format!("-{}", c)).into_iter().chain(Some(format!("--{}", f.long)))).collect()
+}
+
+fn bash_completions() -> String {
+    let mut out = String::new();
+    let names: Vec<_> = COMMANDS.iter().map(|c| c.name).collect();
+    let _ = writeln!(out, "_xch() {{");
+    let _ = writeln!(out, "    local cur prev cmd i");
+    let _ = writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"");
+    let _ = writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"");
+    let _ = writeln!(out, "    case \"$prev\" in");
+    for flag in value_flags() {
+        let _ = writeln!(out, "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;", long_flags(Some(flag).into_iter()).join("|"), flag.choices.join(" "));
+    }
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out, "    cmd=\"\"");
+    let _ = writeln!(out, "    for ((i = 1; i < COMP_CWORD; i++)); do");
+    let _ = writeln!(out, "        case \"${{COMP_WORDS[i]}}\" in");
+    let _ = writeln!(out, "            {}) cmd=\"${{COMP_WORDS[i]}}\"; break ;;", names.join("|"));
+    let _ = writeln!(out, "            -*) ;;");
+    let _ = writeln!(out, "            *) cmd=swap; break ;;");
+    let _ = writeln!(out, "        esac");
+    let _ = writeln!(out, "    done");
+    let _ = writeln!(out, "    case \"$cmd\" in");
+    for command in COMMANDS {
+        let flags = long_flags(command.flags.iter().chain(GLOBAL_FLAGS));
+        let words = if command.name == "completions" { "bash zsh fish".to_string() } else { String::new() };
+        let _ = writeln!(out, "        {})", command.name);
+        let _ = writeln!(out, "            if [[ \"$cur\" == -* ]]; then");
+        let _ = writeln!(out, "                COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", flags.join(" "));
+        if words.is_empty() {
+            let _ = writeln!(out, "            else");
+            let _ = writeln!(out, "                COMPREPLY=($(compgen -f -- \"$cur\"))");
+        } else {
+            let _ = writeln!(out, "            else");
+            let _ = writeln!(out, "                COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", words);
+        }
+        let _ = writeln!(out, "            fi ;;");
+    }
+    let _ = writeln!(out, "        *)");
+    let _ = writeln!(out, "            if [[ \"$cur\" == -* ]]; then");
+    let _ = writeln!(out, "                COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", long_flags(GLOBAL_FLAGS.iter().chain(SWAP.flags)).join(" "));
+    let _ = writeln!(out, "            else");
+    let _ = writeln!(out, "                COMPREPLY=($(compgen -W \"{}\" -- \"$cur\") $(compgen -f -- \"$cur\"))", names.join(" "));
+    let _ = writeln!(out, "            fi ;;");
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out, "complete -o filenames -F _xch xch");
+    out
+}
+
+fn zsh_quote(s: &str) -> String {
+    s.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+fn zsh_completions() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "#compdef xch");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_xch() {{");
+    let _ = writeln!(out, "    local -a commands");
+    let _ = writeln!(out, "    commands=(");
+    for command in COMMANDS {
+        let _ = writeln!(out, "        '{}:{}'", command.name, zsh_quote(command.about));
+    }
+    let _ = writeln!(out, "    )");
+    let _ = writeln!(out, "    if (( CURRENT == 2 )) && [[ \"${{words[CURRENT]}}\" != -* ]]; then");
+    let _ = writeln!(out, "        _describe 'command' commands");
+    let _ = writeln!(out, "        _files");
+    let _ = writeln!(out, "        return");
+    let _ = writeln!(out, "    fi");
+    let _ = writeln!(out, "    case \"${{words[2]}}\" in");
+    for command in COMMANDS {
+        let _ = writeln!(out, "        {})", command.name);
+        let _ = write!(out, "            _arguments");
+        for flag in command.flags.iter().chain(GLOBAL_FLAGS) {
+            let value = match flag.value {
+                Some(name) => format!(":{}:({})", name.to_lowercase(), flag.choices.join(" ")),
+                None => String::new(),
+            };
+            let spec = match flag.short {
+                Some(c) => format!("'(-{c} --{l})'{{-{c},--{l}}}'[{h}]{v}'", c = c, l = flag.long, h = zsh_quote(flag.help), v = value),
+                None => format!("'--{}[{}]{}'", flag.long, zsh_quote(flag.help), value),
+            };
+            let _ = write!(out, " \\\n                {}", spec);
+        }
+        let action = if command.name == "completions" { "(bash zsh fish)" } else { "_files" };
+        let _ = writeln!(out, " \\\n                '*:argument:{}' ;;", action);
+    }
+    let _ = writeln!(out, "        *) _files ;;");
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_xch \"$@\"");
+    out
+}
+
+fn fish_completions() -> String {
+    let mut out = String::new();
+    let names: Vec<_> = COMMANDS.iter().map(|c| c.name).collect();
+    for flag in GLOBAL_FLAGS {
+        let _ = writeln!(out, "complete -c xch{} -l {} -d '{}'", fish_short(flag), flag.long, fish_quote(flag.help));
+    }
+    for command in COMMANDS {
+        let _ = writeln!(out, "complete -c xch -n '__fish_use_subcommand' -a {} -d '{}'", command.name, fish_quote(command.about));
+        for flag in command.flags {
+            let _ = writeln!(out, "complete -c xch -n '__fish_seen_subcommand_from {}'{} -l {}{} -d '{}'", command.name, fish_short(flag), flag.long, fish_choices(flag), fish_quote(flag.help));
+        }
+    }
+    let _ = writeln!(out, "complete -c xch -n '__fish_seen_subcommand_from completions' -f -a 'bash zsh fish'");
+    let _ = writeln!(out, "complete -c xch -n 'not __fish_seen_subcommand_from {}' -l non-atomic -s n -d '{}'", names.join(" "), fish_quote(NON_ATOMIC.help));
+    out
+}
+
+fn fish_choices(flag: &Flag) -> String {
+    if flag.value.is_some() {
+        format!(" -x -a '{}'", flag.choices.join(" "))
+    } else {
+        String::new()
+    }
+}
+
+fn fish_short(flag: &Flag) -> String {
+    flag.short.map(|c| format!(" -s {}", c)).unwrap_or_default()
+}
+
+fn fish_quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
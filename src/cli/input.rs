@@ -0,0 +1,80 @@
+//! Reading pairs of paths from stdin, for `xch --stdin`.
+//!
+//! Without `-0`, every line holds one pair, with both paths separated by a tab. Empty lines are
+//! skipped. With `-0`, every path is terminated by a NUL byte, and two consecutive paths form a
+//! pair, like the output of `find -print0`.
+
+use std::{
+    ffi,
+    fmt,
+    io::{self, BufRead},
+};
+
+/// A pair of paths, or the reason the input could not be read.
+pub type Pair = Result<[ffi::OsString; 2], InputError>;
+
+/// Malformed or unreadable input.
+#[derive(Debug)]
+pub enum InputError {
+    Io(io::Error),
+    /// A line does not contain exactly two paths separated by a tab.
+    Malformed(usize),
+    /// The NUL separated input holds an odd number of paths.
+    Unpaired(ffi::OsString),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputError::Io(ref e) => write!(f, "could not read stdin: {}", e),
+            InputError::Malformed(line) => write!(f, "line {}: expected two paths separated by a tab", line),
+            InputError::Unpaired(ref path) => write!(f, "{:?} has no partner, the input holds an odd number of paths", path),
+        }
+    }
+}
+
+/// Read pairs of paths from `input`.
+pub fn pairs<R: BufRead + 'static>(input: R, nul: bool) -> Box<dyn Iterator<Item = Pair>> {
+    if nul {
+        let mut records = input.split(b'\0');
+        Box::new(::std::iter::from_fn(move || {
+            let first = match records.next()? {
+                Ok(first) => first,
+                Err(e) => return Some(Err(InputError::Io(e))),
+            };
+            Some(match records.next() {
+                Some(Ok(second)) => Ok([to_os_string(first), to_os_string(second)]),
+                Some(Err(e)) => Err(InputError::Io(e)),
+                None => Err(InputError::Unpaired(to_os_string(first))),
+            })
+        }))
+    } else {
+        Box::new(input.split(b'\n').enumerate().filter_map(|(i, line)| {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(InputError::Io(e))),
+            };
+            if line.is_empty() {
+                return None;
+            }
+            let mut parts = line.split(|&b| b == b'\t');
+            Some(match (parts.next(), parts.next(), parts.next()) {
+                (Some(first), Some(second), None) if !first.is_empty() && !second.is_empty() => {
+                    Ok([to_os_string(first.to_vec()), to_os_string(second.to_vec())])
+                }
+                _ => Err(InputError::Malformed(i + 1)),
+            })
+        }))
+    }
+}
+
+#[cfg(unix)]
+fn to_os_string(bytes: Vec<u8>) -> ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    ffi::OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn to_os_string(bytes: Vec<u8>) -> ffi::OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
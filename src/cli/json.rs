@@ -0,0 +1,173 @@
+//! Machine readable output of the `xch` program.
+//!
+//! With `--output json`, every operation prints one JSON object on a single line to stdout:
+//!
+//! ```text
+//! {"operation":"swap","paths":["a","b"],"strategy":"atomic","duration_us":42,"success":true,"error":null,"rollback":"not_needed"}
+//! ```
+//!
+//! The field names and values are stable:
+//!
+//! * `operation`: the command, one of `swap`, `rotate`, `relink`, `watch` or `check`.
+//! * `paths`: the paths as given on the command line. Paths that are not valid UTF-8 are
+//!   converted lossily.
+//! * `strategy`: `unchanged`, `atomic` or `non_atomic`, or `null` if the operation failed. For
+//!   `check`, this is the strategy an exchange would use.
+//! * `duration_us`: wall clock time of the operation in microseconds.
+//! * `success`: whether the operation succeeded, and the `--verify` command passed.
+//! * `error`: `null` on success, otherwise an object with the fields `kind` (see
+//!   `libxch::ErrorKind::as_str`), `errno` (the OS error code or `null`) and `message`. The kind
+//!   is `refused` if the safety checks refused the paths or the prompt was declined.
+//! * `rollback`: `not_needed`, `complete` or `failed`, see `libxch::Rollback::as_str`. It is
+//!   also `complete` if the paths were exchanged back because the `--verify` command failed.
+//! * `verify`: only with `--verify`, an object with the fields `passed`, `error` (why the command
+//!   failed, or `null`) and `revert_strategy` (how the paths were exchanged back, or `null`).
+
+use std::{
+    ffi,
+    fmt::{self, Write},
+    time,
+};
+
+use libxch::{Error, ErrorKind, Rollback, Strategy, Verification};
+
+/// Why an operation did not succeed.
+#[derive(Debug)]
+pub enum Failure {
+    /// The paths were refused before anything was changed, by the safety checks of the program or
+    /// at the `--interactive` prompt.
+    Refused(String),
+    /// The operation failed.
+    Error(Error),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Refused(ref reason) => write!(f, "refused: {}", reason),
+            Failure::Error(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Error(e)
+    }
+}
+
+/// The outcome of one operation, as reported to the user.
+#[derive(Debug)]
+pub struct Report<'a> {
+    pub operation: &'static str,
+    pub paths: &'a [ffi::OsString],
+    pub duration: time::Duration,
+    pub result: Result<Strategy, Failure>,
+    /// The outcome of the `--verify` command, if there was one.
+    pub verification: Option<Verification>,
+}
+
+impl<'a> Report<'a> {
+    /// The report of an operation on `paths` that was refused for `reason`.
+    pub fn refused(operation: &'static str, paths: &'a [ffi::OsString], reason: String) -> Self {
+        Report {
+            operation,
+            paths,
+            duration: time::Duration::from_secs(0),
+            result: Err(Failure::Refused(reason)),
+            verification: None,
+        }
+    }
+
+    /// Check if the operation succeeded and the verification, if any, passed.
+    pub fn success(&self) -> bool {
+        let passed = match self.verification {
+            Some(ref verification) => verification.passed(),
+            None => true,
+        };
+        self.result.is_ok() && passed
+    }
+
+    /// Format the report as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        out.push_str("{\"operation\":");
+        string(&mut out, self.operation);
+        out.push_str(",\"paths\":[");
+        for (i, path) in self.paths.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            string(&mut out, &path.to_string_lossy());
+        }
+        out.push_str("],\"strategy\":");
+        match self.result {
+            Ok(strategy) => string(&mut out, strategy.as_str()),
+            Err(_) => out.push_str("null"),
+        }
+        let _ = write!(out, ",\"duration_us\":{}", self.duration.as_micros());
+        let _ = write!(out, ",\"success\":{}", self.success());
+        out.push_str(",\"error\":");
+        match self.result {
+            Ok(_) => out.push_str("null"),
+            Err(Failure::Refused(ref reason)) => {
+                out.push_str("{\"kind\":");
+                string(&mut out, ErrorKind::Refused.as_str());
+                out.push_str(",\"errno\":null,\"message\":");
+                string(&mut out, reason);
+                out.push('}');
+            }
+            Err(Failure::Error(ref e)) => {
+                out.push_str("{\"kind\":");
+                string(&mut out, e.kind().as_str());
+                match e.raw_os_error() {
+                    Some(errno) => { let _ = write!(out, ",\"errno\":{}", errno); }
+                    None => out.push_str(",\"errno\":null"),
+                }
+                out.push_str(",\"message\":");
+                string(&mut out, &e.to_string());
+                out.push('}');
+            }
+        }
+        out.push_str(",\"rollback\":");
+        let rollback = match self.result {
+            Err(Failure::Refused(_)) => Rollback::NotNeeded,
+            Err(Failure::Error(ref e)) => e.rollback(),
+            Ok(_) if self.success() => Rollback::NotNeeded,
+            Ok(_) => Rollback::Complete,
+        };
+        string(&mut out, rollback.as_str());
+        if let Some(ref verification) = self.verification {
+            let _ = write!(out, ",\"verify\":{{\"passed\":{},\"error\":", verification.passed());
+            match verification.failure {
+                Some(ref e) => string(&mut out, &e.to_string()),
+                None => out.push_str("null"),
+            }
+            out.push_str(",\"revert_strategy\":");
+            match verification.revert {
+                Some(strategy) => string(&mut out, strategy.as_str()),
+                None => out.push_str("null"),
+            }
+            out.push('}');
+        }
+        out.push('}');
+        out
+    }
+}
+
+/// Append `s` as a quoted JSON string.
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
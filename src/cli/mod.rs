@@ -0,0 +1,421 @@
+//! Command line parsing for the `xch` program.
+//!
+//! All commands and flags are described by the static tables in this module. The parser, the
+//! help texts, the man page and the shell completions are all generated from these tables.
+
+use std::{
+    ffi,
+    fmt,
+    time,
+};
+
+use libxch::{Error, ErrorKind, Rollback};
+
+pub mod docs;
+pub mod input;
+pub mod json;
+pub mod safety;
+
+/// A command line flag.
+#[derive(Debug)]
+pub struct Flag {
+    pub short: Option<char>,
+    pub long: &'static str,
+    /// Name of the value this flag takes, if any.
+    pub value: Option<&'static str>,
+    /// The accepted values, if the value is one of a fixed set.
+    pub choices: &'static [&'static str],
+    pub help: &'static str,
+}
+
+/// A subcommand of the program.
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// Positional arguments with their description.
+    pub args: &'static [(&'static str, &'static str)],
+    pub min_args: usize,
+    pub max_args: Option<usize>,
+    pub flags: &'static [Flag],
+}
+
+pub const HELP: Flag = Flag { short: Some('h'), long: "help", value: None, choices: &[], help: "Prints help information" };
+pub const VERSION: Flag = Flag { short: Some('V'), long: "version", value: None, choices: &[], help: "Prints version information" };
+pub const NON_ATOMIC: Flag = Flag { short: Some('n'), long: "non-atomic", value: None, choices: &[], help: "Use non atomic exchange if atomic is not available" };
+pub const VERBOSE: Flag = Flag { short: Some('v'), long: "verbose", value: None, choices: &[], help: "Print each step to stderr, repeat for system calls" };
+pub const OUTPUT: Flag = Flag { short: Some('o'), long: "output", value: Some("FORMAT"), choices: &["text", "json"], help: "Output format, text or json" };
+pub const STDIN: Flag = Flag { short: None, long: "stdin", value: None, choices: &[], help: "Read pairs of paths from stdin, separated by a tab, one pair per line" };
+pub const NUL: Flag = Flag { short: Some('0'), long: "null", value: None, choices: &[], help: "With --stdin, paths are terminated by NUL and taken two at a time" };
+pub const KEEP_GOING: Flag = Flag { short: Some('k'), long: "keep-going", value: None, choices: &[], help: "With --stdin, continue with the next pair after an error" };
+pub const INTERACTIVE: Flag = Flag { short: Some('i'), long: "interactive", value: None, choices: &[], help: "Describe the paths and ask before exchanging them" };
+pub const FORCE: Flag = Flag { short: Some('f'), long: "force", value: None, choices: &[], help: "Exchange a directory with a file, a mount point or a special file" };
+pub const EXEC_BEFORE: Flag = Flag { short: None, long: "exec-before", value: Some("CMD"), choices: &[], help: "Run CMD before exchanging, do not exchange if it fails" };
+pub const EXEC_AFTER: Flag = Flag { short: None, long: "exec-after", value: Some("CMD"), choices: &[], help: "Run CMD after exchanging" };
+pub const REVERT: Flag = Flag { short: None, long: "revert-on-failure", value: None, choices: &[], help: "Exchange the paths back if the --exec-after command fails" };
+pub const VERIFY: Flag = Flag { short: None, long: "verify", value: Some("CMD"), choices: &[], help: "Run CMD after exchanging, exchange the paths back if it fails" };
+pub const VERIFY_TIMEOUT: Flag = Flag { short: None, long: "verify-timeout", value: Some("SECS"), choices: &[], help: "With --verify, fail the check if it runs longer than SECS" };
+pub const CHECKSUM: Flag = Flag { short: None, long: "checksum", value: None, choices: &[], help: "Compare content digests before and after, exchange back if they differ" };
+pub const ROOT: Flag = Flag { short: None, long: "root", value: Some("DIR"), choices: &[], help: "Resolve the paths beneath DIR, as if it was the root directory" };
+pub const NO_SYMLINKS: Flag = Flag { short: None, long: "no-symlinks", value: None, choices: &[], help: "Refuse paths with a symbolic link or .. in a parent directory" };
+pub const SECURE: Flag = Flag { short: None, long: "secure", value: None, choices: &[], help: "Refuse paths that other users than their owners can modify" };
+pub const PRESERVE_LABELS: Flag = Flag { short: None, long: "preserve-labels", value: None, choices: &[], help: "Keep the SELinux context and ACLs of each path for the new content" };
+pub const MARKER: Flag = Flag { short: Some('m'), long: "marker", value: Some("NAME"), choices: &[], help: "File in STAGED that marks it as complete, .ready by default" };
+pub const LOOP: Flag = Flag { short: Some('l'), long: "loop", value: None, choices: &[], help: "Keep watching, and exchange again for every new marker" };
+
+/// Flags accepted by every command.
+pub const GLOBAL_FLAGS: &[Flag] = &[VERBOSE, HELP, VERSION];
+
+pub const SWAP: Command = Command {
+    name: "swap",
+    about: "Exchange the content of two paths",
+    args: &[("<PATH1>", "One path to exchange"), ("<PATH2>", "The other path to exchange")],
+    min_args: 2,
+    max_args: Some(2),
+    flags: &[NON_ATOMIC, INTERACTIVE, FORCE, OUTPUT, EXEC_BEFORE, EXEC_AFTER, REVERT, VERIFY, VERIFY_TIMEOUT, CHECKSUM, PRESERVE_LABELS, SECURE, ROOT, NO_SYMLINKS, STDIN, NUL, KEEP_GOING],
+};
+
+pub const ROTATE: Command = Command {
+    name: "rotate",
+    about: "Rotate the content of paths, each one gets the content of the next",
+    args: &[("<PATH>...", "Paths to rotate, the last one gets the content of the first")],
+    min_args: 2,
+    max_args: None,
+    flags: &[NON_ATOMIC, INTERACTIVE, FORCE, OUTPUT, EXEC_BEFORE, EXEC_AFTER, REVERT, CHECKSUM, PRESERVE_LABELS, SECURE, ROOT, NO_SYMLINKS],
+};
+
+pub const RELINK: Command = Command {
+    name: "relink",
+    about: "Atomically point a symbolic link to a new target",
+    args: &[("<LINK>", "The symbolic link to change or create"), ("<TARGET>", "The new target of the link")],
+    min_args: 2,
+    max_args: Some(2),
+    flags: &[OUTPUT],
+};
+
+pub const WATCH: Command = Command {
+    name: "watch",
+    about: "Wait until a staged path is complete, then exchange it with the live path",
+    args: &[("<STAGED>", "The new content, complete once it contains the marker"), ("<LIVE>", "The path to exchange it with")],
+    min_args: 2,
+    max_args: Some(2),
+    flags: &[MARKER, LOOP, NON_ATOMIC, FORCE, OUTPUT, EXEC_BEFORE, EXEC_AFTER, REVERT, VERIFY, VERIFY_TIMEOUT, CHECKSUM, PRESERVE_LABELS, SECURE, ROOT, NO_SYMLINKS],
+};
+
+pub const CHECK: Command = Command {
+    name: "check",
+    about: "Check if two paths can be exchanged, without changing anything",
+    args: &[("<PATH1>", "One path to exchange"), ("<PATH2>", "The other path to exchange")],
+    min_args: 2,
+    max_args: Some(2),
+    flags: &[NON_ATOMIC, FORCE, OUTPUT, SECURE, ROOT, NO_SYMLINKS],
+};
+
+pub const RECOVER: Command = Command {
+    name: "recover",
+    about: "Restore or delete entries left behind by interrupted exchanges",
+    args: &[("<DIR>...", "Directories to search for left behind entries")],
+    min_args: 1,
+    max_args: None,
+    flags: &[],
+};
+
+pub const COMPLETIONS: Command = Command {
+    name: "completions",
+    about: "Print a completion script for the given shell (bash, zsh or fish)",
+    args: &[("<SHELL>", "The shell to generate completions for")],
+    min_args: 1,
+    max_args: Some(1),
+    flags: &[],
+};
+
+pub const MAN: Command = Command {
+    name: "man",
+    about: "Print the man page",
+    args: &[],
+    min_args: 0,
+    max_args: Some(0),
+    flags: &[],
+};
+
+/// All subcommands, in the order they are documented.
+pub const COMMANDS: &[&Command] = &[&SWAP, &ROTATE, &RELINK, &WATCH, &CHECK, &RECOVER, &COMPLETIONS, &MAN];
+
+/// Exit code for success.
+pub const EXIT_OK: i32 = 0;
+/// Exit code if the requested operation failed for any other reason.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code for an invalid command line.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code if a path does not exist.
+pub const EXIT_NOT_FOUND: i32 = 3;
+/// Exit code if permissions are missing.
+pub const EXIT_PERMISSION: i32 = 4;
+/// Exit code if atomic exchange is not supported.
+pub const EXIT_UNSUPPORTED: i32 = 5;
+/// Exit code if the exchange failed after changing something, but all changes were undone.
+pub const EXIT_ROLLED_BACK: i32 = 6;
+/// Exit code if rolling back failed, so an entry may be left at a temporary name.
+pub const EXIT_ROLLBACK_FAILED: i32 = 7;
+/// Exit code if the exchange was refused by a safety check, or declined at the prompt.
+pub const EXIT_REFUSED: i32 = 8;
+/// Exit code if an `--exec-before` or `--exec-after` command failed.
+pub const EXIT_HOOK: i32 = 9;
+/// Exit code if the `--verify` command failed, so the paths were exchanged back.
+pub const EXIT_VERIFY_FAILED: i32 = 10;
+
+/// All exit codes with their meaning, as documented in the man page.
+pub const EXIT_CODES: &[(i32, &str)] = &[
+    (EXIT_OK, "Success"),
+    (EXIT_FAILURE, "The operation failed for any other reason"),
+    (EXIT_USAGE, "Invalid command line"),
+    (EXIT_NOT_FOUND, "A path does not exist"),
+    (EXIT_PERMISSION, "Permission denied"),
+    (EXIT_UNSUPPORTED, "Atomic exchange is not supported here, retry with --non-atomic"),
+    (EXIT_ROLLED_BACK, "The exchange failed part way, all changes were rolled back"),
+    (EXIT_ROLLBACK_FAILED, "Rolling back failed, run 'xch recover' to find displaced entries"),
+    (EXIT_REFUSED, "The exchange looked unsafe and was refused, or was declined at the prompt"),
+    (EXIT_HOOK, "An --exec-before or --exec-after command failed"),
+    (EXIT_VERIFY_FAILED, "The --verify command failed or timed out, the paths were exchanged back"),
+];
+
+/// The exit code for a failed operation.
+///
+/// A rollback takes precedence over the kind of error, since it tells more about the state the
+/// paths are left in.
+pub fn exit_code(error: &Error) -> i32 {
+    match (error.rollback(), error.kind()) {
+        (Rollback::Failed, _) => EXIT_ROLLBACK_FAILED,
+        (Rollback::Complete, _) => EXIT_ROLLED_BACK,
+        (Rollback::NotNeeded, ErrorKind::NotFound) => EXIT_NOT_FOUND,
+        (Rollback::NotNeeded, ErrorKind::PermissionDenied) => EXIT_PERMISSION,
+        (Rollback::NotNeeded, ErrorKind::Unsupported) => EXIT_UNSUPPORTED,
+        (Rollback::NotNeeded, ErrorKind::Refused) => EXIT_REFUSED,
+        (Rollback::NotNeeded, ErrorKind::Hook) => EXIT_HOOK,
+        (Rollback::NotNeeded, _) => EXIT_FAILURE,
+    }
+}
+
+/// The result of parsing the command line.
+#[derive(Debug)]
+pub enum Parsed {
+    /// Help was requested, for a command or for the program in general.
+    Help(Option<&'static Command>),
+    Version,
+    Run(Invocation),
+}
+
+/// A command together with its flags and arguments.
+#[derive(Debug)]
+pub struct Invocation {
+    pub command: &'static Command,
+    flags: Vec<(&'static str, Option<ffi::OsString>)>,
+    pub args: Vec<ffi::OsString>,
+}
+
+impl Invocation {
+    /// Check if the flag with the given long name was passed.
+    pub fn has(&self, long: &str) -> bool {
+        self.flags.iter().any(|(l, _)| *l == long)
+    }
+
+    /// How often the flag with the given long name was passed.
+    pub fn count(&self, long: &str) -> usize {
+        self.flags.iter().filter(|(l, _)| *l == long).count()
+    }
+
+    /// The value of the flag with the given long name. If it was passed several times, the last
+    /// value wins.
+    pub fn value(&self, long: &str) -> Option<&ffi::OsStr> {
+        self.flags.iter().rev().find(|(l, _)| *l == long).and_then(|(_, v)| v.as_deref())
+    }
+}
+
+/// An invalid command line.
+#[derive(Debug)]
+pub enum ParseError {
+    UnknownFlag(String),
+    MissingValue(&'static Flag),
+    UnexpectedValue(&'static Flag),
+    InvalidValue(&'static Flag, String),
+    ArgCount(&'static Command, usize),
+    /// The first argument named a command that got only one argument, the one given: it was
+    /// probably meant as a path to swap.
+    Ambiguous(&'static Command, ffi::OsString),
+    /// Paths were given as arguments together with `--stdin`.
+    ArgsWithStdin(&'static Command),
+    /// Two flags were given that can't be used together.
+    Conflict(&'static Flag, &'static Flag),
+    /// The first flag only makes sense together with the second.
+    Requires(&'static Flag, &'static Flag),
+}
+
+impl ParseError {
+    /// The command the error refers to, if it is known.
+    pub fn command(&self) -> Option<&'static Command> {
+        match *self {
+            ParseError::ArgCount(command, _) | ParseError::Ambiguous(command, _) | ParseError::ArgsWithStdin(command) => Some(command),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnknownFlag(ref flag) => write!(f, "unknown flag '{}'", flag),
+            ParseError::MissingValue(flag) => write!(f, "flag '--{}' needs a value", flag.long),
+            ParseError::UnexpectedValue(flag) => write!(f, "flag '--{}' does not take a value", flag.long),
+            ParseError::Ambiguous(command, ref arg) => {
+                let arg = arg.to_string_lossy();
+                write!(f, "'{0}' is a command and needs other arguments; to swap a path named '{0}' with '{1}', run 'xch swap {0} {1}' or 'xch -- {0} {1}'", command.name, arg)
+            }
+            ParseError::ArgsWithStdin(command) => write!(f, "'{}' takes no paths as arguments with '--{}'", command.name, STDIN.long),
+            ParseError::Conflict(a, b) => write!(f, "'--{}' can't be used together with '--{}'", a.long, b.long),
+            ParseError::Requires(a, b) => write!(f, "'--{}' needs '--{}'", a.long, b.long),
+            ParseError::InvalidValue(flag, ref value) if flag.choices.is_empty() => write!(f, "invalid value '{}' for flag '--{}'", value, flag.long),
+            ParseError::InvalidValue(flag, ref value) => write!(f, "invalid value '{}' for flag '--{}', expected one of {}", value, flag.long, flag.choices.join(", ")),
+            ParseError::ArgCount(command, got) => match command.max_args {
+                Some(max) if max == command.min_args => write!(f, "'{}' needs exactly {} arguments, got {} instead", command.name, max, got),
+                Some(max) => write!(f, "'{}' needs {} to {} arguments, got {} instead", command.name, command.min_args, max, got),
+                None => write!(f, "'{}' needs at least {} arguments, got {} instead", command.name, command.min_args, got),
+            },
+        }
+    }
+}
+
+/// Parse the command line arguments, without the program name.
+///
+/// The first argument that is not a flag selects the command. If it does not name a command, the
+/// `swap` command is used and the argument is treated as a path, so that `xch <PATH1> <PATH2>`
+/// keeps working. A path named like a command has to follow `swap` or `--` to be swapped.
+/// Everything after `--` is treated as an argument.
+pub fn parse<I: IntoIterator<Item = ffi::OsString>>(args: I) -> Result<Parsed, ParseError> {
+    let mut args = args.into_iter();
+    let mut command: Option<&'static Command> = None;
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut only_args = false;
+    let mut named = false;
+
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy().into_owned();
+        if only_args || text == "-" || !text.starts_with('-') {
+            if command.is_none() && !only_args {
+                command = Some(match COMMANDS.iter().cloned().find(|c| c.name == text) {
+                    Some(command) => {
+                        named = true;
+                        command
+                    }
+                    None => {
+                        positional.push(arg.clone());
+                        &SWAP
+                    }
+                });
+            } else {
+                positional.push(arg);
+            }
+            continue;
+        }
+        if text == "--" {
+            only_args = true;
+            command.get_or_insert(&SWAP);
+            continue;
+        }
+        // Legacy spelling of the recover command.
+        if text == "--gc" && command.is_none() {
+            command = Some(&RECOVER);
+            continue;
+        }
+
+        let available = |f: &&'static Flag| command.unwrap_or(&SWAP).flags.iter().chain(GLOBAL_FLAGS).any(|g| g.long == f.long);
+        if let Some(long) = text.strip_prefix("--") {
+            let (name, inline) = match long.find('=') {
+                Some(i) => (&long[..i], Some(ffi::OsString::from(&long[i + 1..]))),
+                None => (long, None),
+            };
+            let flag = all_flags().find(|f| f.long == name).filter(available)
+                .ok_or_else(|| ParseError::UnknownFlag(text.clone()))?;
+            let value = match (flag.value, inline) {
+                (None, None) => None,
+                (None, Some(_)) => return Err(ParseError::UnexpectedValue(flag)),
+                (Some(_), Some(v)) => Some(v),
+                (Some(_), None) => Some(args.next().ok_or(ParseError::MissingValue(flag))?),
+            };
+            flags.push((flag.long, value));
+        } else {
+            // A group of bundled short flags, like `-nv`. A flag with a value takes the rest of the
+            // group, or the next argument.
+            for (i, c) in text[1..].char_indices() {
+                let flag = all_flags().find(|f| f.short == Some(c)).filter(available)
+                    .ok_or_else(|| ParseError::UnknownFlag(format!("-{}", c)))?;
+                if flag.value.is_some() {
+                    let rest = &text[1 + i + c.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        args.next().ok_or(ParseError::MissingValue(flag))?
+                    } else {
+                        rest.into()
+                    };
+                    flags.push((flag.long, Some(value)));
+                    break;
+                }
+                flags.push((flag.long, None));
+            }
+        }
+    }
+
+    for (long, value) in &flags {
+        let flag = all_flags().find(|f| f.long == *long).expect("parsed flags are known");
+        if let Some(value) = value {
+            let value = value.to_string_lossy();
+            if !flag.choices.is_empty() && !flag.choices.contains(&&*value) {
+                return Err(ParseError::InvalidValue(flag, value.into_owned()));
+            }
+            if flag.long == VERIFY_TIMEOUT.long && parse_seconds(&value).is_none() {
+                return Err(ParseError::InvalidValue(flag, value.into_owned()));
+            }
+        }
+    }
+    if flags.iter().any(|(l, _)| *l == HELP.long) {
+        return Ok(Parsed::Help(command));
+    }
+    let command = command.unwrap_or(&SWAP);
+    if flags.iter().any(|(l, _)| *l == VERSION.long) {
+        return Ok(Parsed::Version);
+    }
+    let given = |flag: &Flag| flags.iter().any(|(l, _)| *l == flag.long);
+    if given(&STDIN) && given(&INTERACTIVE) {
+        return Err(ParseError::Conflict(&INTERACTIVE, &STDIN));
+    }
+    if given(&VERIFY_TIMEOUT) && !given(&VERIFY) {
+        return Err(ParseError::Requires(&VERIFY_TIMEOUT, &VERIFY));
+    }
+    if flags.iter().any(|(l, _)| *l == STDIN.long) {
+        if !positional.is_empty() {
+            return Err(ParseError::ArgsWithStdin(command));
+        }
+    } else if positional.len() < command.min_args || command.max_args.is_some_and(|max| positional.len() > max) {
+        if named && positional.len() == 1 {
+            return Err(ParseError::Ambiguous(command, positional.swap_remove(0)));
+        }
+        return Err(ParseError::ArgCount(command, positional.len()));
+    }
+    Ok(Parsed::Run(Invocation {
+        command,
+        flags,
+        args: positional,
+    }))
+}
+
+/// Parse a number of seconds, like `30` or `0.5`.
+pub fn parse_seconds(value: &str) -> Option<time::Duration> {
+    value.parse::<f64>().ok().and_then(|secs| time::Duration::try_from_secs_f64(secs).ok())
+}
+
+/// All flags of all commands, without duplicates.
+fn all_flags() -> impl Iterator<Item = &'static Flag> {
+    let mut seen: Vec<&'static str> = Vec::new();
+    GLOBAL_FLAGS.iter()
+        .chain(COMMANDS.iter().flat_map(|c| c.flags.iter()))
+        .filter(move |f| if seen.contains(&f.long) { false } else { seen.push(f.long); true })
+}
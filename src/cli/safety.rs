@@ -0,0 +1,131 @@
+//! Safety checks and confirmation prompts before exchanging paths.
+//!
+//! Some exchanges are almost always typos, like a directory with a file or a mount point. The
+//! CLI refuses them unless `--force` is given, and `--interactive` describes both paths and asks
+//! before each exchange. Special files are refused by the library itself.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path,
+    time,
+};
+
+use libxch::{Backend, FileType};
+
+/// Reasons not to exchange two paths without asking, looked up through `backend`.
+pub fn hazards<F: Backend>(backend: &F, path1: &path::Path, path2: &path::Path) -> Vec<String> {
+    let mut hazards = Vec::new();
+    let (entry1, entry2) = (backend.lookup(path1), backend.lookup(path2));
+    for (path, entry) in [(path1, &entry1), (path2, &entry2)] {
+        match *entry {
+            Ok(ref entry) if entry.mount_point && entry.path.parent().is_none() => {
+                hazards.push(format!("{} is the root directory", path.display()));
+            }
+            Ok(ref entry) if entry.mount_point => hazards.push(format!("{} is a mount point, the root of a file system", path.display())),
+            _ => (),
+        }
+    }
+    if let (Ok(entry1), Ok(entry2)) = (entry1, entry2) {
+        let (is_dir1, is_dir2) = (entry1.file_type == FileType::Dir, entry2.file_type == FileType::Dir);
+        if is_dir1 != is_dir2 {
+            let (dir, file) = if is_dir1 { (path1, path2) } else { (path2, path1) };
+            hazards.push(format!("{} is a directory, but {} is not", dir.display(), file.display()));
+        }
+    }
+    hazards
+}
+
+/// A one line description of the path: its type, size, owner and age, looked up through `backend`.
+///
+/// The size of a directory is the total size of all files in it, at any depth, so that two large
+/// trees exchanged by a typo stand out. The backend resolves the parent directories under its own
+/// rules, the object is then read at the path it resolved to.
+pub fn describe<F: Backend>(backend: &F, path: &path::Path) -> String {
+    let entry = match backend.lookup(path) {
+        Ok(entry) => entry,
+        Err(e) => return format!("{}: {}", path.display(), e),
+    };
+    let meta = fs::symlink_metadata(&entry.path).ok();
+    let mut what = match entry.file_type {
+        FileType::Symlink => {
+            let target = fs::read_link(&entry.path).map(|t| t.display().to_string()).unwrap_or_default();
+            format!("symbolic link to {}", target)
+        }
+        FileType::Dir => {
+            let (entries, size) = tree_size(&entry.path);
+            format!("directory with {} entries, {} in total", entries, format_size(size))
+        }
+        FileType::File => format!("file, {}", format_size(meta.as_ref().map_or(0, fs::Metadata::len))),
+        file_type => file_type.as_str().to_string(),
+    };
+    if let Some(owner) = entry.ownership {
+        what.push_str(&format!(", owned by uid {}", owner.uid));
+    }
+    match meta.and_then(|m| m.modified().ok()).and_then(|t| t.elapsed().ok()) {
+        Some(age) => format!("{}: {}, modified {} ago", path.display(), what, format_age(age)),
+        None => format!("{}: {}", path.display(), what),
+    }
+}
+
+/// Describe the paths and the hazards, then ask for confirmation on stdin.
+pub fn confirm<F: Backend, P: AsRef<path::Path>>(backend: &F, paths: &[P], hazards: &[String]) -> bool {
+    for path in paths {
+        eprintln!("{}", describe(backend, path.as_ref()));
+    }
+    for hazard in hazards {
+        eprintln!("warning: {}", hazard);
+    }
+    eprint!("exchange them? [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// The number of entries in the directory `dir`, at any depth, and the total size of its files.
+///
+/// Symbolic links are not followed. Directories that can't be read count as empty.
+fn tree_size(dir: &path::Path) -> (u64, u64) {
+    let (mut entries, mut size) = (0, 0);
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            entries += 1;
+            match entry.metadata() {
+                Ok(ref meta) if meta.is_dir() => pending.push(entry.path()),
+                Ok(meta) => size += meta.len(),
+                Err(_) => (),
+            }
+        }
+    }
+    (entries, size)
+}
+
+/// Format a size like `512 bytes` or `1.5 MiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a duration like `5m` or `2d 3h`.
+pub fn format_age(age: time::Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    fmt,
+    fs,
+    io,
+    path,
+};
+
+/// How the content of a file was copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloneMethod {
+    /// The data blocks are shared with the original, using `FICLONE` on copy-on-write file
+    /// systems like btrfs and XFS. Takes no time and no space, whatever the size of the file.
+    Reflink,
+    /// Copied by the kernel with `copy_file_range`, which can still share the blocks or use a
+    /// server-side copy on some file systems.
+    CopyRange,
+    /// Copied by reading and writing the content.
+    Copy,
+}
+
+impl CloneMethod {
+    /// A short name, like `reflink`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CloneMethod::Reflink => "reflink",
+            CloneMethod::CopyRange => "copy_file_range",
+            CloneMethod::Copy => "copy",
+        }
+    }
+}
+
+impl fmt::Display for CloneMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Copy the regular file `from` to the new path `to`, as cheaply as possible, with its permissions.
+///
+/// On Linux, the copy shares the data blocks with the original on file systems that support it,
+/// and is made by the kernel otherwise. Elsewhere, the content is copied. Fails if `to` exists.
+/// If the copy fails, the partial copy is removed.
+///
+/// Returns the method that was used.
+pub fn clone_file<A: AsRef<path::Path>, B: AsRef<path::Path>>(from: A, to: B) -> io::Result<CloneMethod> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let source = fs::File::open(from)?;
+    let meta = source.metadata()?;
+    if !meta.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a regular file", from.display())));
+    }
+    let target = fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+    let res = copy_contents(&source, &target, meta.len()).and_then(|method| {
+        target.set_permissions(meta.permissions())?;
+        Ok(method)
+    });
+    match res {
+        Ok(method) => {
+            trace!("cloned {} to {} ({})", from.display(), to.display(), method);
+            Ok(method)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(to);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn copy_contents(from: &fs::File, to: &fs::File, len: u64) -> io::Result<CloneMethod> {
+    crate::platform::clone_contents(from, to, len)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_contents(mut from: &fs::File, mut to: &fs::File, _len: u64) -> io::Result<CloneMethod> {
+    io::copy(&mut from, &mut to).map(|_| CloneMethod::Copy)
+}
@@ -3,27 +3,114 @@ pub enum Error {
     Fs(::std::io::Error),
     PlatformError(crate::platform::PlatformError),
     LogicError(String),
+    /// Rolling back failed. Holds the rollback error, followed by the error that caused the rollback.
     ChainError(Box<Error>, Box<Error>),
+    /// The contained error happened after some changes were made, which were rolled back.
+    RolledBack(Box<Error>),
+    /// One path is inside the other, so they can't be exchanged.
+    Nested { ancestor: ::std::path::PathBuf, descendant: ::std::path::PathBuf },
+    /// The path is the root of a mounted file system, which can't be renamed.
+    MountPoint(::std::path::PathBuf),
+    /// The path is a FIFO, a socket or a device node, and the options do not allow those.
+    SpecialFile { path: ::std::path::PathBuf, file_type: crate::backend::FileType },
+    /// The path or its parent directory can be modified by other users than their owners, and the
+    /// options require secure paths. `reason` says which one.
+    Insecure { path: ::std::path::PathBuf, reason: &'static str },
+    /// The content digest of the path after the exchange differs from the digest of the other
+    /// path before the exchange.
+    ChecksumMismatch(::std::path::PathBuf),
+    /// The security labels of the path could not be set after the exchange, see
+    /// `Options::preserve_labels`.
+    Relabel { path: ::std::path::PathBuf, error: ::std::io::Error },
+    /// A hook set in the options failed. `hook` is `"before"` or `"after"`.
+    Hook { hook: &'static str, error: Box<Error> },
     NotImplemented,
 }
 
-impl ::std::error::Error for Error {
-    fn description(&self) -> &str {
+impl Error {
+    /// A short, human readable summary of this error, without any underlying cause.
+    fn summary(&self) -> &str {
         match *self {
             Error::Fs(_)
             | Error::PlatformError(_)
-            | Error::ChainError(_, _) => "Could not exchange paths",
-            |
+            | Error::ChainError(_, _)
+            | Error::RolledBack(_) => "Could not exchange paths",
             Error::LogicError(ref s) => s,
+            Error::Nested { .. } => "Cannot exchange a path with its own ancestor",
+            Error::MountPoint(_) => "Refusing to exchange the root of a file system",
+            Error::SpecialFile { .. } => "Refusing to exchange a special file",
+            Error::Insecure { .. } => "Refusing to exchange an insecure path",
+            Error::ChecksumMismatch(_) => "Content changed during the exchange",
+            Error::Relabel { .. } => "Could not preserve the security labels",
+            Error::Hook { .. } => "Hook failed",
             Error::NotImplemented => "Not supported on this platform"
         }
     }
 
+    /// The OS error code behind this error, if there is one.
+    ///
+    /// For a `ChainError`, this is the code of the error that caused the rollback.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match *self {
+            Error::Fs(ref e) | Error::Relabel { error: ref e, .. } => e.raw_os_error(),
+            Error::PlatformError(ref e) => e.raw_os_error(),
+            Error::ChainError(_, ref e) | Error::RolledBack(ref e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// The category of this error.
+    ///
+    /// For a `ChainError` or `RolledBack`, this is the category of the error that caused the
+    /// rollback.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Fs(ref e) | Error::Relabel { error: ref e, .. } => ErrorKind::from_io(e),
+            Error::PlatformError(ref e) => e.kind(),
+            Error::LogicError(_) => ErrorKind::Other,
+            Error::ChainError(_, ref e) | Error::RolledBack(ref e) => e.kind(),
+            Error::Nested { .. } => ErrorKind::Nested,
+            Error::MountPoint(_) | Error::SpecialFile { .. } | Error::Insecure { .. } => ErrorKind::Refused,
+            Error::ChecksumMismatch(_) => ErrorKind::ChecksumMismatch,
+            Error::Hook { .. } => ErrorKind::Hook,
+            Error::NotImplemented => ErrorKind::Unsupported,
+        }
+    }
+
+    /// Whether changes had to be rolled back, and if that worked.
+    pub fn rollback(&self) -> Rollback {
+        match *self {
+            Error::ChainError(_, _) => Rollback::Failed,
+            Error::RolledBack(_) => Rollback::Complete,
+            _ => Rollback::NotNeeded,
+        }
+    }
+
+    /// The underlying cause of an error, or the error itself if it has no cause.
+    ///
+    /// Hook and relabel errors keep their own message, which names the hook or the path.
+    fn cause_to_string(&self) -> String {
+        use ::std::error::Error;
+        match *self {
+            self::Error::Hook { .. } | self::Error::Relabel { .. } => self.to_string(),
+            _ => self.source().map(ToString::to_string).unwrap_or_else(|| self.to_string()),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        self.summary()
+    }
+
     fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
         match *self {
             Error::Fs(ref e) => Some(e),
             Error::PlatformError(ref e) => Some(e),
             Error::ChainError(ref e1, ref e2) => e1.source().or_else(|| e2.source()),
+            Error::RolledBack(ref e) => e.source(),
+            Error::Relabel { ref error, .. } => Some(error),
+            Error::Hook { ref error, .. } => Some(&**error),
             _ => None,
         }
     }
@@ -32,10 +119,26 @@ impl ::std::error::Error for Error {
 impl ::std::fmt::Display for Error {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         use ::std::error::Error;
-        if let Some(cause) = self.source() {
-            write!(f, "{}: {}", self.description(), cause)
+        if let self::Error::ChainError(ref rollback, ref cause) = *self {
+            write!(f, "{}: {}; rollback failed: {}", self.summary(), cause.cause_to_string(), rollback.cause_to_string())
+        } else if let self::Error::RolledBack(ref cause) = *self {
+            write!(f, "{}: {}; all changes were rolled back", self.summary(), cause.cause_to_string())
+        } else if let self::Error::Nested { ref ancestor, ref descendant } = *self {
+            write!(f, "{}: {} contains {}", self.summary(), ancestor.display(), descendant.display())
+        } else if let self::Error::MountPoint(ref path) | self::Error::ChecksumMismatch(ref path) = *self {
+            write!(f, "{}: {}", self.summary(), path.display())
+        } else if let self::Error::SpecialFile { ref path, file_type } = *self {
+            write!(f, "{}: {} is a {}", self.summary(), path.display(), file_type.as_str())
+        } else if let self::Error::Insecure { ref path, reason } = *self {
+            write!(f, "{}: {} {}", self.summary(), path.display(), reason)
+        } else if let self::Error::Relabel { ref path, ref error } = *self {
+            write!(f, "{} of {}: {}", self.summary(), path.display(), error)
+        } else if let self::Error::Hook { hook, ref error } = *self {
+            write!(f, "{} {} the exchange: {}", self.summary(), hook, error)
+        } else if let Some(cause) = self.source() {
+            write!(f, "{}: {}", self.summary(), cause)
         } else {
-            write!(f, "{}", self.description())
+            write!(f, "{}", self.summary())
         }
     }
 }
@@ -65,3 +168,110 @@ impl From<crate::platform::PlatformError> for Error {
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Categories of errors, independent of the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// One of the paths does not exist.
+    NotFound,
+    /// Missing permissions to change one of the paths.
+    PermissionDenied,
+    /// The exchange is not supported by the platform or the file system.
+    Unsupported,
+    /// The paths are on different file systems.
+    CrossDevice,
+    /// One of the paths is in use, for example because it is a mount point.
+    Busy,
+    /// One path is inside the other.
+    Nested,
+    /// A path is a mount point, a special file or insecure, which the options do not allow, or
+    /// has a symbolic link in a parent directory where none is allowed.
+    Refused,
+    /// A hook set in the options failed.
+    Hook,
+    /// The content of a path changed during the exchange.
+    ChecksumMismatch,
+    /// Any other error.
+    Other,
+}
+
+impl ErrorKind {
+    /// A stable, machine readable name for this kind, in snake case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::CrossDevice => "cross_device",
+            ErrorKind::Busy => "busy",
+            ErrorKind::Nested => "nested",
+            ErrorKind::Refused => "refused",
+            ErrorKind::Hook => "hook",
+            ErrorKind::ChecksumMismatch => "checksum_mismatch",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Classify an I/O error.
+    pub(crate) fn from_io(e: &::std::io::Error) -> ErrorKind {
+        match e.kind() {
+            ::std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            ::std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            _ => match e.raw_os_error() {
+                Some(code) if code == os_codes::EXDEV => ErrorKind::CrossDevice,
+                Some(code) if os_codes::EBUSY.contains(&code) => ErrorKind::Busy,
+                Some(code) if os_codes::ELOOP.contains(&code) => ErrorKind::Refused,
+                _ => ErrorKind::Other,
+            }
+        }
+    }
+}
+
+/// The outcome of rolling back a failed exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rollback {
+    /// Nothing was changed before the error, so there was nothing to roll back.
+    NotNeeded,
+    /// All changes were rolled back.
+    Complete,
+    /// Rolling back failed, some paths may be left at a temporary name.
+    Failed,
+}
+
+impl Rollback {
+    /// A stable, machine readable name for this outcome, in snake case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rollback::NotNeeded => "not_needed",
+            Rollback::Complete => "complete",
+            Rollback::Failed => "failed",
+        }
+    }
+}
+
+#[cfg(unix)]
+mod os_codes {
+    pub const EXDEV: i32 = 18;
+    pub const EBUSY: &[i32] = &[16];
+    #[cfg(target_os = "linux")]
+    pub const ELOOP: &[i32] = &[40];
+    /// The BSDs, including macOS.
+    #[cfg(not(target_os = "linux"))]
+    pub const ELOOP: &[i32] = &[62];
+}
+
+#[cfg(windows)]
+mod os_codes {
+    /// `ERROR_NOT_SAME_DEVICE`
+    pub const EXDEV: i32 = 17;
+    /// `ERROR_SHARING_VIOLATION`, `ERROR_LOCK_VIOLATION` and `ERROR_BUSY`
+    pub const EBUSY: &[i32] = &[32, 33, 170];
+    pub const ELOOP: &[i32] = &[];
+}
+
+#[cfg(not(any(unix, windows)))]
+mod os_codes {
+    pub const EXDEV: i32 = -1;
+    pub const EBUSY: &[i32] = &[];
+    pub const ELOOP: &[i32] = &[];
+}
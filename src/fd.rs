@@ -0,0 +1,87 @@
+use std::{
+    env,
+    fs,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path,
+};
+
+use crate::{
+    error::Result,
+    platform,
+};
+
+/// Atomically exchange the directory entries of two open files.
+///
+/// The paths of both files are resolved through `/proc/self/fd`, then exchanged like `xch` does.
+/// Afterwards, the path of `file1` refers to `file2` and the other way round. Both files stay
+/// open and keep their content.
+///
+/// Fails if a file has no directory entry, for example because it was opened with `O_TMPFILE`
+/// or deleted, or if its entry was renamed or replaced since it was opened. There is a short
+/// window between resolving the paths and exchanging them, in which a concurrent rename of one
+/// of the entries goes unnoticed.
+///
+/// Only available on Linux.
+pub fn xch_files(file1: &fs::File, file2: &fs::File) -> Result<()> {
+    let path1 = linked_path(file1)?;
+    let path2 = linked_path(file2)?;
+    debug!("exchanging open files at {} and {}", path1.display(), path2.display());
+    if path1 == path2 {
+        return Ok(());
+    }
+    platform::xch(&path1, &path2)
+}
+
+/// Exchange the content of two open regular files by copying it.
+///
+/// The content of `file1` is copied to a temporary file, then the content of `file2` to `file1`,
+/// then the temporary copy to `file2`. The copies share the data blocks on copy-on-write file
+/// systems, and use `copy_file_range` otherwise. The temporary file is created next
+/// to `file1` if its directory is known, and in the temporary directory of the system otherwise.
+///
+/// This works for files without directory entry, but **it is not atomic**: other processes can
+/// observe partially copied content, and an error can leave `file1` or `file2` partially
+/// overwritten. Both files need to be opened for reading and writing. The file positions are not
+/// changed.
+///
+/// Only available on Linux.
+pub fn swap_contents(file1: &fs::File, file2: &fs::File) -> Result<()> {
+    let meta1 = file1.metadata()?;
+    let meta2 = file2.metadata()?;
+    if !meta1.is_file() || !meta2.is_file() {
+        return Err("Can only swap the content of regular files".into());
+    }
+    if (meta1.dev(), meta1.ino()) == (meta2.dev(), meta2.ino()) {
+        return Ok(());
+    }
+
+    let temp = match linked_path(file1).ok().as_ref().and_then(|p| p.parent()) {
+        Some(dir) => platform::open_tmpfile(dir).or_else(|_| platform::open_tmpfile(&env::temp_dir()))?,
+        None => platform::open_tmpfile(&env::temp_dir())?,
+    };
+    debug!("swapping {} bytes of fd {} with {} bytes of fd {}", meta1.len(), file1.as_raw_fd(), meta2.len(), file2.as_raw_fd());
+    let method = platform::clone_contents(file1, &temp, meta1.len())?;
+    trace!("copied fd {} to a temporary file ({})", file1.as_raw_fd(), method);
+    let method = platform::clone_contents(file2, file1, meta2.len())?;
+    trace!("copied fd {} to fd {} ({})", file2.as_raw_fd(), file1.as_raw_fd(), method);
+    file1.set_len(meta2.len())?;
+    let method = platform::clone_contents(&temp, file2, meta1.len())?;
+    trace!("copied the temporary file to fd {} ({})", file2.as_raw_fd(), method);
+    file2.set_len(meta1.len())?;
+    Ok(())
+}
+
+/// The path of the directory entry of an open file.
+fn linked_path(file: &fs::File) -> Result<path::PathBuf> {
+    let meta = file.metadata()?;
+    let fd_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    if meta.nlink() == 0 {
+        return Err(format!("{} has no directory entry", fd_path).into());
+    }
+    let path = fs::read_link(&fd_path)?;
+    let current = fs::symlink_metadata(&path)?;
+    if (current.dev(), current.ino()) != (meta.dev(), meta.ino()) {
+        return Err(format!("{} no longer refers to the open file {}", path.display(), fd_path).into());
+    }
+    Ok(path)
+}
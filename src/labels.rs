@@ -0,0 +1,105 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path,
+};
+
+/// The extended attributes that make up the security labels of an object: its SELinux context
+/// and its POSIX ACLs.
+pub const LABEL_ATTRIBUTES: [&str; 3] = ["security.selinux", "system.posix_acl_access", "system.posix_acl_default"];
+
+/// The security labels of an object, the values of the extended attributes in
+/// `LABEL_ATTRIBUTES` that are set on it.
+///
+/// Labels belong to a location rather than to the content: a tree that is built elsewhere and
+/// exchanged into place keeps the labels of the place it was built in. See
+/// `Options::preserve_labels`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Labels {
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl Labels {
+    /// No labels at all.
+    pub fn new() -> Self {
+        Labels::default()
+    }
+
+    /// The raw value of the attribute `name`, if it is set.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.values.get(name).map(Vec::as_slice)
+    }
+
+    /// Set the attribute `name` to `value`.
+    pub fn set<S: Into<String>>(&mut self, name: S, value: Vec<u8>) {
+        self.values.insert(name.into(), value);
+    }
+
+    /// Remove the attribute `name`, if it is set.
+    pub fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    /// The names of the attributes that are set, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+
+    /// Check if no attribute is set.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The names of the attributes that differ between `self` and `other`, including the ones
+    /// that are only set in one of them.
+    pub fn differences(&self, other: &Labels) -> Vec<String> {
+        let names: BTreeSet<_> = self.names().chain(other.names()).collect();
+        names.into_iter().filter(|&name| self.get(name) != other.get(name)).map(String::from).collect()
+    }
+}
+
+/// Read the security labels of the object at `path`, without following a final symbolic link.
+///
+/// Attributes the file system does not support count as not set. On platforms other than
+/// Linux, there are never any labels.
+pub fn labels<P: AsRef<path::Path>>(path: P) -> io::Result<Labels> {
+    read(path.as_ref())
+}
+
+/// Set the attribute `name` of the object at `path` to `value`, or remove it for `None`, without
+/// following a final symbolic link.
+///
+/// Removing an attribute that is not set succeeds. Setting the SELinux context needs the
+/// permission to relabel the object, setting the ACLs needs ownership of it.
+pub fn set_label<P: AsRef<path::Path>>(path: P, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+    write(path.as_ref(), name, value)
+}
+
+#[cfg(target_os = "linux")]
+fn read(path: &path::Path) -> io::Result<Labels> {
+    let mut labels = Labels::new();
+    for &name in &LABEL_ATTRIBUTES {
+        if let Some(value) = crate::platform::get_xattr(path, name)? {
+            labels.set(name, value);
+        }
+    }
+    Ok(labels)
+}
+
+#[cfg(target_os = "linux")]
+fn write(path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+    crate::platform::set_xattr(path, name, value)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read(_path: &path::Path) -> io::Result<Labels> {
+    Ok(Labels::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write(path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+    match value {
+        None => Ok(()),
+        Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot set {} of {} on this platform", name, path.display()))),
+    }
+}
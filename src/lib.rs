@@ -11,19 +11,67 @@
 
 use std::path;
 
-pub use error::Error;
+pub use backend::{Backend, Entry, FileType, Native, Ownership};
+#[cfg(feature = "checksum")]
+pub use checksum::{digest, Digest};
+pub use cloning::{clone_file, CloneMethod};
+pub use error::{Error, ErrorKind, Rollback};
+pub use labels::{labels, set_label, Labels, LABEL_ATTRIBUTES};
+pub use options::{Options, Strategy, Verification};
+pub use orphans::{scan_orphans, Orphan};
+pub use relink::relink;
+pub use stage::{stage, CopyMode, Stage};
+pub use watch::wait_for;
+#[cfg(target_os = "linux")]
+pub use fd::{swap_contents, xch_files};
+#[cfg(target_os = "linux")]
+pub use publish::publish;
+#[cfg(target_os = "linux")]
+pub use rooted::Rooted;
 
+#[macro_use]
+mod logging;
+mod backend;
+#[cfg(feature = "checksum")]
+mod checksum;
+mod cloning;
+mod labels;
 mod platform;
+mod preflight;
 mod non_atomic;
+mod options;
+mod orphans;
+mod relink;
+mod stage;
+mod watch;
 mod error;
+#[cfg(target_os = "linux")]
+mod fd;
+#[cfg(target_os = "linux")]
+mod publish;
+#[cfg(target_os = "linux")]
+mod rooted;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 /// Exchange the content of the objects pointed to by the two paths.
 ///
 /// This can be used to swap the content of two files, but it also works with directories.
 /// **This operation is atomic**, meaning if the content at one path changed, the other path will
 /// also have changed. If the operation can't be done atomically, it will fail.
+///
+/// Exchanging two paths that refer to the same object (the same path, or two hard links to the
+/// same file) succeeds without changing anything. If one path is inside the other, this fails
+/// with `Error::Nested` before anything is changed.
 pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> error::Result<()> {
-    platform::xch(path1, path2)
+    xch_with(&Native, path1, path2)
+}
+
+/// Atomically exchange the content of two paths, using the given backend.
+///
+/// Behaves like `xch`, but all file system access goes through `backend`.
+pub fn xch_with<F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>>(backend: &F, path1: A, path2: B) -> error::Result<()> {
+    Options::new().xch_with(backend, path1, path2).map(|_| ())
 }
 
 /// Exchange the content of the object pointed to by the two paths.
@@ -31,11 +79,15 @@ pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> er
 /// This can be used to swap the content of two files, but it also works with directories.
 /// **This operation may not be atomic**. If available, it will try to use the platform specific,
 /// atomic operations. If they are not implemented, this will fallback to a non-atomic exchange.
+///
+/// Identical and nested paths are handled the same way as in `xch`.
 pub fn xch_non_atomic<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> error::Result<()> {
-    let res = platform::xch(&path1, &path2);
-    if let Err(error::Error::NotImplemented) = res {
-        non_atomic::xch(&path1, &path2)
-    } else {
-        res
-    }
+    xch_non_atomic_with(&Native, path1, path2)
+}
+
+/// Exchange the content of two paths, possibly non-atomically, using the given backend.
+///
+/// Behaves like `xch_non_atomic`, but all file system access goes through `backend`.
+pub fn xch_non_atomic_with<F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>>(backend: &F, path1: A, path2: B) -> error::Result<()> {
+    Options::new().non_atomic(true).xch_with(backend, path1, path2).map(|_| ())
 }
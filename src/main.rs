@@ -3,94 +3,546 @@
 // Licensed under the MIT License <LICENSE or http://opensource.org/licenses/MIT>.
 // This file may not be copied, modified, or distributed except according to those terms.
 
-use libxch;
+use std::{
+    env,
+    ffi,
+    fs,
+    io::{self, BufRead, Write},
+    path,
+    process,
+    thread,
+    time,
+};
 
-use std::env;
+use libxch::{scan_orphans, Backend, Error, Native, Options, Orphan, Strategy};
 
-use libxch::{xch, xch_non_atomic};
+mod cli;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const PROG_INFO: &'static str = concat!("xch ", env!("CARGO_PKG_VERSION"), "\n", env!("CARGO_PKG_AUTHORS"), "\nA utility to quickly swap the content of files or directories.\n");
+use crate::cli::{docs, exit_code, json::{Failure, Report}, safety, Invocation, Parsed, EXIT_FAILURE, EXIT_OK, EXIT_REFUSED, EXIT_USAGE, EXIT_VERIFY_FAILED};
 
-const USAGE: &'static str = "USAGE:
-    xch [FLAGS] [--] <PATH1> <PATH2>
-
-FLAGS:
-    -h, --help          Prints help information
-    -n, --non-atomic    Use non atomic exchange if atomic is not available
-    -V, --version       Prints version information
-
-ARGS:
-    <PATH1>    One path to exchange
-    <PATH2>    The other path to exchange";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    let mut args = env::args_os().skip(1);
-    let mut show_help = false;
-    let mut show_version = false;
-    let mut non_atomic = false;
-    let mut treat_as_path = false;
-    let mut paths = Vec::new();
-
-    while let Some(arg) = args.next() {
-        if !treat_as_path && (arg == "-h" || arg == "--help") {
-            show_help = true;
-            continue;
+    let invocation = match cli::parse(env::args_os().skip(1)) {
+        Ok(Parsed::Help(Some(command))) => {
+            println!("{}", docs::usage(command));
+            return;
         }
-        if !treat_as_path && (arg == "-V" || arg == "--version") {
-            show_version = true;
-            continue;
+        Ok(Parsed::Help(None)) => {
+            println!("{}", docs::help());
+            return;
         }
-        if !treat_as_path && (arg == "-n" || arg == "--non-atomic") {
-            non_atomic = true;
-            continue;
+        Ok(Parsed::Version) => {
+            println!("xch {}", VERSION);
+            return;
         }
-        if !treat_as_path && arg == "--" {
-            treat_as_path = true;
-            continue;
+        Ok(Parsed::Run(invocation)) => invocation,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!();
+            match e.command() {
+                Some(command) => eprintln!("{}", docs::usage(command)),
+                None => eprintln!("{}", docs::help()),
+            }
+            ::std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    init_logging(invocation.count(cli::VERBOSE.long));
+    let exit_code = match invocation.command.name {
+        "swap" => swap(&invocation),
+        "rotate" => rotate(&invocation),
+        "relink" => relink(&invocation),
+        "watch" => watch(&invocation),
+        "check" => check(&invocation),
+        "recover" => collect_garbage(&invocation.args),
+        "completions" => completions(&invocation),
+        "man" => {
+            print!("{}", docs::man_page());
+            EXIT_OK
+        }
+        name => unreachable!("command {} is not implemented", name),
+    };
+    ::std::process::exit(exit_code);
+}
+
+/// Print log messages of the library to stderr, more of them with higher verbosity.
+fn init_logging(verbosity: usize) {
+    struct StderrLogger;
+
+    impl log::Log for StderrLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target().starts_with("libxch")
         }
 
-        paths.push(arg);
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                eprintln!("{}: {}", record.level().as_str().to_lowercase(), record.args());
+            }
+        }
+
+        fn flush(&self) {}
     }
 
-    if show_help {
-        println!("{}", PROG_INFO);
-        println!("{}", USAGE);
-        return;
+    let level = match verbosity {
+        0 => return,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
     }
+}
 
-    if show_version {
-        println!("xch {}", VERSION);
-        return;
+fn options(invocation: &Invocation) -> Options {
+    let force = invocation.has(cli::FORCE.long);
+    let mut options = Options::new()
+        .non_atomic(invocation.has(cli::NON_ATOMIC.long))
+        // A mount point is one of the hazards the user confirmed at the prompt.
+        .allow_mount_points(force || invocation.has(cli::INTERACTIVE.long))
+        .allow_special_files(force)
+        .secure(invocation.has(cli::SECURE.long))
+        .preserve_labels(invocation.has(cli::PRESERVE_LABELS.long))
+        .revert_on_hook_failure(invocation.has(cli::REVERT.long));
+    if invocation.has(cli::PRESERVE_LABELS.long) {
+        options = options.on_relabel(|path, changed| eprintln!("relabeled {}: {}", path.display(), changed.join(", ")));
+    }
+    if let Some(cmd) = invocation.value(cli::EXEC_BEFORE.long) {
+        let cmd = cmd.to_os_string();
+        options = options.on_before(move |path1, path2| exec_hook(&cmd, path1, path2, None));
     }
+    if let Some(cmd) = invocation.value(cli::EXEC_AFTER.long) {
+        let cmd = cmd.to_os_string();
+        options = options.on_after(move |path1, path2| exec_hook(&cmd, path1, path2, None));
+    }
+    options.verify_checksums(invocation.has(cli::CHECKSUM.long))
+}
+
+/// Run a `--exec-before`, `--exec-after` or `--verify` command with the shell.
+///
+/// The paths are passed in `XCH_PATH1` and `XCH_PATH2`. The output of the command goes to
+/// stderr, so it does not mix with the JSON output. If the command runs longer than `timeout`,
+/// it is killed and counts as failed.
+fn exec_hook(cmd: &ffi::OsStr, path1: &path::Path, path2: &path::Path, timeout: Option<time::Duration>) -> Result<(), Error> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut command = process::Command::new(shell);
+    command.arg(flag)
+        .arg(cmd)
+        .env("XCH_PATH1", path1)
+        .env("XCH_PATH2", path2)
+        .stdin(process::Stdio::null())
+        .stdout(io::stderr());
+    // In its own process group, so that a timeout also stops the processes the shell started.
+    #[cfg(unix)]
+    ::std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn().map_err(|e| format!("could not run {:?}: {}", cmd, e))?;
+    let status = match timeout {
+        None => child.wait()?,
+        Some(timeout) => {
+            let deadline = time::Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if time::Instant::now() >= deadline {
+                    kill_group(&mut child);
+                    let _ = child.wait();
+                    return Err(format!("{:?} timed out after {:?}", cmd, timeout).into());
+                }
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        }
+    };
+    if !status.success() {
+        return Err(format!("{:?} failed with {}", cmd, status).into());
+    }
+    Ok(())
+}
+
+/// Kill a child process started by `exec_hook`, with all processes in its group.
+#[cfg(unix)]
+fn kill_group(child: &mut process::Child) {
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const SIGKILL: i32 = 9;
+    if unsafe { kill(-(child.id() as i32), SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
 
-    if paths.len() < 2 {
-        eprintln!("error: need exactly two path to exchange, got {} instead", paths.len());
-        println!("{}", USAGE);
-        ::std::process::exit(1);
+#[cfg(not(unix))]
+fn kill_group(child: &mut process::Child) {
+    let _ = child.kill();
+}
+
+fn json_output(invocation: &Invocation) -> bool {
+    invocation.value(cli::OUTPUT.long) == Some("json".as_ref())
+}
+
+/// Run the operation of the invocation and measure how long it takes.
+fn run<F: FnOnce() -> Result<Strategy, Error>>(invocation: &Invocation, operation: F) -> Report<'_> {
+    run_on(invocation, &invocation.args, operation)
+}
+
+/// Like `run`, for paths that were not given as arguments.
+fn run_on<'a, F: FnOnce() -> Result<Strategy, Error>>(invocation: &Invocation, paths: &'a [ffi::OsString], operation: F) -> Report<'a> {
+    let start = time::Instant::now();
+    let result = operation().map_err(Failure::Error);
+    Report {
+        operation: invocation.command.name,
+        paths,
+        duration: start.elapsed(),
+        result,
+        verification: None,
     }
+}
 
-    if paths.len() > 2 {
-        eprintln!("error: need exactly two path to exchange, got {} instead", paths.len());
-        eprintln!("-> first extra argument was: {:?}", paths[2]);
-        println!("{}", USAGE);
-        ::std::process::exit(1);
+/// Print the outcome of an operation and return the exit code.
+fn finish(invocation: &Invocation, report: &Report, context: &str) -> i32 {
+    let failure = report.verification.as_ref().and_then(|v| v.failure.as_ref());
+    if json_output(invocation) {
+        println!("{}", report.to_json());
+    } else if let Err(Failure::Error(ref e)) = report.result {
+        eprintln!("error: {}: {}", context, e);
+    } else if let Some(failure) = failure {
+        eprintln!("error: {}: verification failed, exchanged the paths back: {}", context, failure);
+    }
+    match report.result {
+        Err(ref failure) => failure_code(failure),
+        Ok(_) if failure.is_some() => EXIT_VERIFY_FAILED,
+        Ok(_) => EXIT_OK,
     }
+}
 
-    let path1 = paths.remove(0);
-    let path2 = paths.remove(0);
+fn failure_code(failure: &Failure) -> i32 {
+    match *failure {
+        Failure::Refused(_) => EXIT_REFUSED,
+        Failure::Error(ref e) => exit_code(e),
+    }
+}
 
-    let xch_result = if non_atomic {
-        xch_non_atomic(path1, path2)
+/// Check that the paths can be exchanged safely, asking the user with `--interactive`.
+///
+/// Returns `Err` with the reason if the paths should not be exchanged. With `--force`, no
+/// safety checks are done.
+fn guard<F: Backend, P: AsRef<path::Path>>(invocation: &Invocation, backend: &F, paths: &[P]) -> Result<(), String> {
+    let hazards: Vec<_> = if invocation.has(cli::FORCE.long) {
+        Vec::new()
     } else {
-        xch(path1, path2)
+        paths.windows(2).flat_map(|pair| safety::hazards(backend, pair[0].as_ref(), pair[1].as_ref())).collect()
     };
-    let exit_code = match xch_result {
-        Ok(_) => 0,
+    let json = json_output(invocation);
+    if invocation.has(cli::INTERACTIVE.long) {
+        if safety::confirm(backend, paths, &hazards) {
+            return Ok(());
+        }
+        if !json {
+            eprintln!("not exchanged");
+        }
+        return Err("declined at the prompt".to_string());
+    }
+    if hazards.is_empty() {
+        return Ok(());
+    }
+    if !json {
+        for hazard in &hazards {
+            eprintln!("error: refusing to exchange: {}", hazard);
+        }
+        eprintln!("pass --force to exchange anyway, or --interactive to confirm");
+    }
+    Err(hazards.join("; "))
+}
+
+/// Report paths that `guard` refused and return the exit code.
+fn refuse(invocation: &Invocation, paths: &[ffi::OsString], reason: String) -> i32 {
+    finish(invocation, &Report::refused(invocation.command.name, paths, reason), "")
+}
+
+fn swap(invocation: &Invocation) -> i32 {
+    with_root(invocation, |backend| swap_on(invocation, backend)).unwrap_or_else(|| swap_on(invocation, &Native))
+}
+
+fn swap_on<F: Backend>(invocation: &Invocation, backend: &F) -> i32 {
+    if invocation.has(cli::STDIN.long) {
+        return swap_stdin(invocation, backend);
+    }
+    if let Err(reason) = guard(invocation, backend, &invocation.args) {
+        return refuse(invocation, &invocation.args, reason);
+    }
+    let report = swap_pair(invocation, &options(invocation), backend, &invocation.args);
+    finish(invocation, &report, "could not swap files")
+}
+
+/// Run `operation` with a backend resolving the paths beneath the `--root` directory, or without
+/// following symbolic links with `--no-symlinks` or `--secure`.
+///
+/// With `--secure`, the backend checks the ownership again on the directories it renames in.
+/// Returns `None` if none of these flags was given.
+#[cfg(target_os = "linux")]
+fn with_root<F: FnOnce(&libxch::Rooted) -> i32>(invocation: &Invocation, operation: F) -> Option<i32> {
+    let no_symlinks = invocation.has(cli::NO_SYMLINKS.long);
+    let secure = invocation.has(cli::SECURE.long);
+    let (backend, dir) = match invocation.value(cli::ROOT.long) {
+        Some(root) => (libxch::Rooted::open(root).map(|b| b.no_symlinks(no_symlinks)), format!("{:?}", root)),
+        None if no_symlinks || secure => (libxch::Rooted::hardened(), "/ or the current directory".to_string()),
+        None => return None,
+    };
+    let backend = backend.map(|b| b.secure(secure));
+    Some(match backend {
+        Ok(backend) => operation(&backend),
         Err(e) => {
-            eprintln!("error: could not swap files: {}", e);
-            1
+            eprintln!("error: could not open {}: {}", dir, e);
+            exit_code(&e.into())
         }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn with_root<F: FnOnce(&Native) -> i32>(invocation: &Invocation, _operation: F) -> Option<i32> {
+    if !invocation.has(cli::ROOT.long) && !invocation.has(cli::NO_SYMLINKS.long) {
+        return None;
+    }
+    eprintln!("error: --root and --no-symlinks are only supported on Linux");
+    Some(cli::EXIT_UNSUPPORTED)
+}
+
+/// Exchange two paths, followed by the `--verify` command if there is one.
+fn swap_pair<'a, F: Backend>(invocation: &Invocation, options: &Options, backend: &F, paths: &'a [ffi::OsString]) -> Report<'a> {
+    let verify = match invocation.value(cli::VERIFY.long) {
+        Some(cmd) => cmd,
+        None => return run_on(invocation, paths, || options.xch_with(backend, &paths[0], &paths[1])),
     };
-    std::process::exit(exit_code);
+    let timeout = invocation.value(cli::VERIFY_TIMEOUT.long).and_then(|v| cli::parse_seconds(&v.to_string_lossy()));
+    let mut verification = None;
+    let mut report = run_on(invocation, paths, || {
+        options.xch_verified_with(backend, &paths[0], &paths[1], |path1, path2| exec_hook(verify, path1, path2, timeout))
+            .map(|v| verification.insert(v).exchange)
+    });
+    report.verification = verification;
+    report
+}
+
+/// Swap every pair of paths read from stdin and print a summary.
+///
+/// Stops at the first error, unless `--keep-going` was given. The exit code is the one of the
+/// first error.
+fn swap_stdin<F: Backend>(invocation: &Invocation, backend: &F) -> i32 {
+    let keep_going = invocation.has(cli::KEEP_GOING.long);
+    let options = options(invocation);
+    let (mut swapped, mut failed) = (0, 0);
+    let mut status = EXIT_OK;
+
+    for pair in cli::input::pairs(io::stdin().lock(), invocation.has(cli::NUL.long)) {
+        let code = match pair {
+            Ok(paths) => match guard(invocation, backend, &paths) {
+                Ok(()) => {
+                    let report = swap_pair(invocation, &options, backend, &paths);
+                    let context = format!("could not swap {:?} and {:?}", paths[0], paths[1]);
+                    finish(invocation, &report, &context)
+                }
+                Err(reason) => refuse(invocation, &paths, reason),
+            },
+            Err(e) => {
+                eprintln!("error: {}", e);
+                EXIT_USAGE
+            }
+        };
+        if code == EXIT_OK {
+            swapped += 1;
+            continue;
+        }
+        failed += 1;
+        if status == EXIT_OK {
+            status = code;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+    eprintln!("swapped: {}, failed: {}", swapped, failed);
+    status
+}
+
+fn rotate(invocation: &Invocation) -> i32 {
+    with_root(invocation, |backend| rotate_on(invocation, backend)).unwrap_or_else(|| rotate_on(invocation, &Native))
+}
+
+fn rotate_on<F: Backend>(invocation: &Invocation, backend: &F) -> i32 {
+    if let Err(reason) = guard(invocation, backend, &invocation.args) {
+        return refuse(invocation, &invocation.args, reason);
+    }
+    let report = run(invocation, || options(invocation).rotate_with(backend, &invocation.args));
+    finish(invocation, &report, "could not rotate files")
+}
+
+fn relink(invocation: &Invocation) -> i32 {
+    let report = run(invocation, || libxch::relink(&invocation.args[0], &invocation.args[1]).map(|_| Strategy::Atomic));
+    finish(invocation, &report, "could not change link")
+}
+
+/// Wait for the marker in the staged path, remove it and exchange the staged with the live path.
+///
+/// With `--loop`, wait for the next marker afterwards. A failed `--verify` command only reverts
+/// that exchange, every other error stops the loop.
+fn watch(invocation: &Invocation) -> i32 {
+    with_root(invocation, |backend| watch_on(invocation, backend)).unwrap_or_else(|| watch_on(invocation, &Native))
+}
+
+/// Watch for the marker with `backend` doing the exchanges.
+///
+/// The marker is resolved by the backend as well, so with `--root` it is beneath the root
+/// directory too.
+fn watch_on<F: Backend + Markers>(invocation: &Invocation, backend: &F) -> i32 {
+    let marker = path::Path::new(&invocation.args[0]).join(invocation.value(cli::MARKER.long).unwrap_or_else(|| ".ready".as_ref()));
+    let options = options(invocation);
+    loop {
+        if let Err(e) = backend.wait_for(&marker) {
+            eprintln!("error: could not watch {}: {}", marker.display(), e);
+            return EXIT_FAILURE;
+        }
+        // The marker would end up in the live path otherwise, and make the old content, which is
+        // now staged, look complete.
+        if let Err(e) = backend.remove_marker(&marker) {
+            eprintln!("error: could not remove {}: {}", marker.display(), e);
+            return EXIT_FAILURE;
+        }
+        if let Err(reason) = guard(invocation, backend, &invocation.args) {
+            return refuse(invocation, &invocation.args, reason);
+        }
+        let report = swap_pair(invocation, &options, backend, &invocation.args);
+        let code = finish(invocation, &report, "could not swap files");
+        if !invocation.has(cli::LOOP.long) || (code != EXIT_OK && code != EXIT_VERIFY_FAILED) {
+            return code;
+        }
+    }
+}
+
+/// How `watch` waits for and removes the marker, through the same backend as the exchange.
+trait Markers {
+    fn wait_for(&self, marker: &path::Path) -> io::Result<()>;
+    fn remove_marker(&self, marker: &path::Path) -> io::Result<()>;
+}
+
+impl Markers for Native {
+    fn wait_for(&self, marker: &path::Path) -> io::Result<()> {
+        libxch::wait_for(marker)
+    }
+
+    fn remove_marker(&self, marker: &path::Path) -> io::Result<()> {
+        fs::remove_file(marker)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Markers for libxch::Rooted {
+    fn wait_for(&self, marker: &path::Path) -> io::Result<()> {
+        libxch::Rooted::wait_for(self, marker)
+    }
+
+    fn remove_marker(&self, marker: &path::Path) -> io::Result<()> {
+        self.remove_file(marker)
+    }
+}
+
+fn check(invocation: &Invocation) -> i32 {
+    with_root(invocation, |backend| check_on(invocation, backend)).unwrap_or_else(|| check_on(invocation, &Native))
+}
+
+fn check_on<F: Backend>(invocation: &Invocation, backend: &F) -> i32 {
+    let path1 = &invocation.args[0];
+    let path2 = &invocation.args[1];
+    let report = run(invocation, || {
+        options(invocation).check_with(backend, path1, path2).map(|changes| match changes {
+            false => Strategy::Unchanged,
+            true if backend.supports_exchange() => Strategy::Atomic,
+            true => Strategy::NonAtomic,
+        })
+    });
+    if json_output(invocation) {
+        return finish(invocation, &report, "");
+    }
+    match report.result {
+        Ok(Strategy::Unchanged) => {
+            println!("{:?} and {:?} are the same, exchanging them does nothing", path1, path2);
+            EXIT_OK
+        }
+        Ok(_) => {
+            println!("{:?} and {:?} can be exchanged", path1, path2);
+            EXIT_OK
+        }
+        Err(ref failure) => {
+            eprintln!("error: {:?} and {:?} can not be exchanged: {}", path1, path2, failure);
+            failure_code(failure)
+        }
+    }
+}
+
+fn completions(invocation: &Invocation) -> i32 {
+    let shell = invocation.args[0].to_string_lossy();
+    match docs::completions(&shell) {
+        Some(script) => {
+            print!("{}", script);
+            EXIT_OK
+        }
+        None => {
+            eprintln!("error: unsupported shell '{}', expected one of bash, zsh or fish", shell);
+            EXIT_USAGE
+        }
+    }
+}
+
+/// Search the directories for orphaned entries and ask the user what to do with each of them.
+fn collect_garbage(dirs: &[ffi::OsString]) -> i32 {
+    let stdin = io::stdin();
+    let mut answers = stdin.lock().lines();
+    let mut status = EXIT_OK;
+
+    for dir in dirs {
+        let orphans = match scan_orphans(dir) {
+            Ok(orphans) => orphans,
+            Err(e) => {
+                eprintln!("error: could not search {:?}: {}", dir, e);
+                status = exit_code(&e);
+                continue;
+            }
+        };
+        for orphan in orphans {
+            print_orphan(&orphan);
+            let prompt = if orphan.origin.is_some() { "restore (r), delete (d) or skip (s)? [s] " } else { "delete (d) or skip (s)? [s] " };
+            print!("{}", prompt);
+            let _ = io::stdout().flush();
+            let answer = match answers.next() {
+                Some(Ok(answer)) => answer,
+                _ => {
+                    println!();
+                    return status;
+                }
+            };
+            let res = match answer.trim() {
+                "r" | "restore" if orphan.origin.is_some() => orphan.restore(),
+                "d" | "delete" => orphan.remove(),
+                _ => continue,
+            };
+            if let Err(e) = res {
+                eprintln!("error: could not clean up {}: {}", orphan.path.display(), e);
+                status = exit_code(&e);
+            }
+        }
+    }
+    status
+}
+
+fn print_orphan(orphan: &Orphan) {
+    println!("{}", orphan.path.display());
+    println!("    size:   {} bytes", orphan.size);
+    if let Some(age) = orphan.age {
+        println!("    age:    {}", safety::format_age(age));
+    }
+    if let Some(ref origin) = orphan.origin {
+        println!("    origin: {}", origin.display());
+    }
+    if let Some(ref target) = orphan.target {
+        println!("    target: {}", target.display());
+    }
 }
@@ -1,16 +1,23 @@
 use std::{
-    fs,
     io,
     path,
 };
 
-use uuid;
+use crate::{
+    backend::Backend,
+    error::{Error, Result},
+    orphans,
+};
+
+/// Prefix of the temporary names used while exchanging paths.
+pub const TEMP_PREFIX: &str = ".xch-tmp-";
 
-use crate::error::{Error, Result};
+/// How often to try a new temporary name if the chosen one already exists.
+const CLAIM_ATTEMPTS: usize = 8;
 
 /// Exchange files/directories by non-atomic renames.
 ///
-/// This tries to swap files or directories by calling `std::fs::rename` 3 times.
+/// This tries to swap files or directories by calling `Backend::rename` 3 times.
 /// 1. Rename path1 to a temporary path
 /// 2. Rename path2 to path1
 /// 3. Rename the temporary path to path2
@@ -18,25 +25,77 @@ use crate::error::{Error, Result};
 /// This does not happen atomically. This means there is a time frame were path1 does not exist. Or
 /// path1 does exists but path2 does not.
 ///
-/// On error the changes are rolled back, if possible.
-pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> Result<()> {
+/// The temporary path is created in `staging`. Without a staging directory, it is created next to
+/// path1, in the current directory if path1 is a single component. Only if path1 has no parent at
+/// all, because it is a root directory, is it created next to path2.
+/// It is claimed by a rename that never replaces an existing entry, so a colliding name can never
+/// destroy anything. A sidecar file next to it records the original paths, so that the entry can
+/// be found and restored with `scan_orphans` should the process be killed during the exchange.
+///
+/// `check` is called after the last rename. On error, including an error from `check`, the
+/// changes are rolled back, if possible.
+pub fn xch<F, A, B, C>(backend: &F, staging: Option<&path::Path>, path1: A, path2: B, check: C) -> Result<()>
+    where F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>, C: FnOnce() -> Result<()>
+{
     let path1 = path1.as_ref();
     let path2 = path2.as_ref();
 
-    let one_parent = path1.parent()
-        .ok_or_else::<Error, _>(|| format!("Could not find parent directory for {}", path1.display()).into())
-        .or_else(|_|
-            path2.parent()
-                .ok_or_else::<Error, _>(|| format!("Could not find parent directory for {}", path2.display()).into())
-        )?;
-    let unique_name = format!("{}", uuid::Uuid::new_v4().to_hyphenated());
-    let temp_name = one_parent.join(unique_name);
-
-    let mut transaction = Transaction::new();
-    transaction.record_rename(path1, &temp_name);
+    let staging = match staging.or_else(|| path1.parent()).or_else(|| path2.parent()) {
+        Some(dir) => dir,
+        None => return Err(format!("Could not find parent directory for {} or {}", path1.display(), path2.display()).into()),
+    };
+    let sidecar = orphans::sidecar_content(&backend.lookup(path1)?.path, &backend.lookup(path2)?.path);
+    let (temp_name, has_sidecar) = claim(backend, staging, path1, sidecar.as_ref().map(AsRef::as_ref))?;
+    debug!("moved {} to temporary name {}", path1.display(), temp_name.display());
+
+    let mut transaction = Transaction::new(backend);
+    transaction.record_executed(path1, &temp_name);
     transaction.record_rename(path2, path1);
     transaction.record_rename(&temp_name, path2);
-    transaction.commit()
+    let res = transaction.commit().and_then(|()| check().map_err(|e| transaction.abort(e)));
+    if let Err(ref e) = res {
+        debug!("non-atomic exchange of {} and {} failed: {}", path1.display(), path2.display(), e);
+    }
+
+    // If rolling back failed, the sidecar is needed to find the displaced entry again.
+    if has_sidecar && !matches!(res, Err(Error::ChainError(_, _))) {
+        let _ = backend.remove_sidecar(&orphans::sidecar_path(&temp_name));
+    }
+    res
+}
+
+/// Move `path` to a new temporary name in `dir`.
+///
+/// This never replaces an existing entry. If the chosen name is already taken, a new one is tried.
+/// If `sidecar` is given, it is written next to the temporary name before moving `path`. Failing
+/// to write it is not an error, the returned flag tells if it was written.
+fn claim<F: Backend>(backend: &F, dir: &path::Path, path: &path::Path, sidecar: Option<&[u8]>) -> Result<(path::PathBuf, bool)> {
+    let mut attempts = 1;
+    loop {
+        let unique_name = format!("{}{}", TEMP_PREFIX, uuid::Uuid::new_v4().to_hyphenated());
+        let temp_name = dir.join(unique_name);
+        let sidecar_path = orphans::sidecar_path(&temp_name);
+        trace!("claiming temporary name {} (attempt {} of {})", temp_name.display(), attempts, CLAIM_ATTEMPTS);
+        let res = match sidecar.map(|content| backend.write_sidecar(&sidecar_path, content)) {
+            Some(Err(e)) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+            Some(Ok(())) => match backend.rename_noreplace(path, &temp_name) {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    let _ = backend.remove_sidecar(&sidecar_path);
+                    Err(e)
+                }
+            },
+            _ => backend.rename_noreplace(path, &temp_name).map(|_| false),
+        };
+        match res {
+            Ok(has_sidecar) => return Ok((temp_name, has_sidecar)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < CLAIM_ATTEMPTS => {
+                debug!("temporary name {} is taken, trying another one", temp_name.display());
+                attempts += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
 }
 
 /// Structure to hold information about a rename to execute.
@@ -47,26 +106,32 @@ struct RenameCmd<'a> {
 
 impl<'a> RenameCmd<'a> {
     /// Execute the rename represented by this struct.
-    fn exec(&self) -> io::Result<()> {
-        fs::rename(self.from, self.to)
+    fn exec<F: Backend>(&self, backend: &F) -> io::Result<()> {
+        let res = backend.rename(self.from, self.to);
+        trace!("rename {} -> {}: {:?}", self.from.display(), self.to.display(), res);
+        res
     }
 
     /// Rollback the changes created by this struct.
-    fn rollback(self) -> io::Result<()> {
-        fs::rename(self.to, self.from)
+    fn rollback<F: Backend>(self, backend: &F) -> io::Result<()> {
+        let res = backend.revert(self.from, self.to);
+        trace!("revert {} -> {}: {:?}", self.to.display(), self.from.display(), res);
+        res
     }
 }
 
 /// Represents a new file system transaction.
-struct Transaction<'a> {
+struct Transaction<'a, F: Backend> {
+    backend: &'a F,
     to_exec: Vec<RenameCmd<'a>>,
     successful_exec: Vec<RenameCmd<'a>>,
 }
 
-impl<'a> Transaction<'a> {
-    /// Create a new transaction.
-    fn new() -> Transaction<'a> {
+impl<'a, F: Backend> Transaction<'a, F> {
+    /// Create a new transaction operating on the given backend.
+    fn new(backend: &'a F) -> Transaction<'a, F> {
         Transaction {
+            backend,
             to_exec: Vec::new(),
             successful_exec: Vec::new(),
         }
@@ -80,11 +145,20 @@ impl<'a> Transaction<'a> {
         })
     }
 
+    /// Record a rename that was already executed, so that it is undone on rollback.
+    fn record_executed(&mut self, from: &'a path::Path, to: &'a path::Path) {
+        self.successful_exec.push(RenameCmd {
+            from,
+            to,
+        })
+    }
+
     /// Rollback all changes caused by this transaction.
-    fn rollback(mut self) -> Result<()> {
+    fn rollback(&mut self) -> Result<()> {
+        debug!("rolling back {} renames", self.successful_exec.len());
         loop {
             if let Some(cmd) = self.successful_exec.pop() {
-                if let Err(e) = cmd.rollback() {
+                if let Err(e) = cmd.rollback(self.backend) {
                     return Err(e.into());
                 }
             } else {
@@ -97,12 +171,15 @@ impl<'a> Transaction<'a> {
     ///
     /// On success, all renames are executed as they were recorded.
     /// On error, this calls `rollback()` and tries to undo all changes.
-    fn commit(mut self) -> Result<()> {
+    fn commit(&mut self) -> Result<()> {
+        for cmd in &self.to_exec {
+            debug!("planned: rename {} -> {}", cmd.from.display(), cmd.to.display());
+        }
         let res = {
             let mut drain = self.to_exec.drain(..);
             loop {
                 if let Some(cmd) = drain.next() {
-                    let ex = cmd.exec();
+                    let ex = cmd.exec(self.backend);
                     if let Err(e) = ex {
                         break Err(e);
                     } else {
@@ -114,16 +191,16 @@ impl<'a> Transaction<'a> {
             }
         };
 
-        if let Err(rename_err) = res {
-            let rollback_res = self.rollback();
-            if let Err(e) = rollback_res {
-                let rename_err: Error = rename_err.into();
-                Err(Error::ChainError(Box::new(e), Box::new(rename_err)))
-            } else {
-                Err(rename_err.into())
-            }
-        } else {
-            res.map_err(Into::into)
+        res.map_err(|e| self.abort(e.into()))
+    }
+
+    /// Roll back after `err` happened, and return the error to report.
+    fn abort(&mut self, err: Error) -> Error {
+        let started = !self.successful_exec.is_empty();
+        match self.rollback() {
+            Err(e) => Error::ChainError(Box::new(e), Box::new(err)),
+            Ok(()) if started => Error::RolledBack(Box::new(err)),
+            Ok(()) => err,
         }
     }
 }
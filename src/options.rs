@@ -0,0 +1,573 @@
+use std::{
+    fmt,
+    path,
+    sync::Arc,
+};
+
+use crate::{
+    backend::{Backend, Native},
+    error::{Error, ErrorKind, Result},
+    non_atomic,
+    preflight,
+};
+
+/// How an exchange was carried out.
+///
+/// The variants are ordered from the strongest to the weakest guarantee, so the strategy of
+/// several exchanges is the maximum of the single strategies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Strategy {
+    /// Both paths refer to the same object, nothing had to be changed.
+    Unchanged,
+    /// The paths were exchanged in one atomic operation.
+    Atomic,
+    /// The paths were exchanged by the non-atomic fallback.
+    NonAtomic,
+}
+
+impl Strategy {
+    /// A stable, machine readable name for this strategy, in snake case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Strategy::Unchanged => "unchanged",
+            Strategy::Atomic => "atomic",
+            Strategy::NonAtomic => "non_atomic",
+        }
+    }
+}
+
+/// The outcome of an exchange followed by a check, see `Options::xch_verified`.
+#[derive(Debug)]
+pub struct Verification {
+    /// How the paths were exchanged.
+    pub exchange: Strategy,
+    /// Why the check failed, or `None` if it passed.
+    pub failure: Option<Error>,
+    /// How the paths were exchanged back after the check failed, or `None` if it passed.
+    pub revert: Option<Strategy>,
+}
+
+impl Verification {
+    /// Check if the check passed, so the paths stay exchanged.
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Configurable exchange of two paths.
+///
+/// The free functions `xch` and `xch_non_atomic` use the default options. Use this to change
+/// how the exchange is done:
+///
+/// ```no_run
+/// let options = libxch::Options::new()
+///     .non_atomic(true)
+///     .staging_dir("/srv/.staging");
+/// options.xch("/srv/current", "/srv/next")?;
+/// # Ok::<(), libxch::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    non_atomic: bool,
+    staging_dir: Option<path::PathBuf>,
+    policy: preflight::Policy,
+    hooks: Hooks,
+    #[cfg(feature = "checksum")]
+    checksums: bool,
+    labels: bool,
+}
+
+type Check<'a> = Box<dyn FnOnce() -> Result<()> + 'a>;
+
+type Hook = Arc<dyn Fn(&path::Path, &path::Path) -> Result<()> + Send + Sync>;
+type Notification = Arc<dyn Fn(&path::Path, &path::Path) + Send + Sync>;
+type Relabeled = Arc<dyn Fn(&path::Path, &[String]) + Send + Sync>;
+
+/// Callbacks around every exchange.
+#[derive(Clone, Default)]
+struct Hooks {
+    before: Option<Hook>,
+    after: Option<Hook>,
+    rollback: Option<Notification>,
+    relabel: Option<Relabeled>,
+    revert_on_failure: bool,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("before", &self.before.is_some())
+            .field("after", &self.after.is_some())
+            .field("rollback", &self.rollback.is_some())
+            .field("relabel", &self.relabel.is_some())
+            .field("revert_on_failure", &self.revert_on_failure)
+            .finish()
+    }
+}
+
+impl Hooks {
+    fn run(hook: &Option<Hook>, name: &'static str, path1: &path::Path, path2: &path::Path) -> Result<()> {
+        match *hook {
+            Some(ref hook) => hook(path1, path2).map_err(|e| {
+                debug!("{} hook failed: {}", name, e);
+                Error::Hook { hook: name, error: Box::new(e) }
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn rolled_back(&self, path1: &path::Path, path2: &path::Path) {
+        if let Some(ref hook) = self.rollback {
+            hook(path1, path2);
+        }
+    }
+}
+
+impl Options {
+    /// Create the default options: an atomic exchange, without fallback.
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// Fall back to a non-atomic exchange if atomic exchange is not available.
+    ///
+    /// This covers platforms without atomic exchange as well as file systems that do not support
+    /// it, any error of kind `ErrorKind::Unsupported`.
+    pub fn non_atomic(mut self, non_atomic: bool) -> Self {
+        self.non_atomic = non_atomic;
+        self
+    }
+
+    /// Directory in which the non-atomic fallback creates its temporary entry.
+    ///
+    /// By default, this is the parent directory of the first path, or of the second path if the
+    /// first one is a root directory. The staging directory has to be on the same file system as
+    /// both paths.
+    pub fn staging_dir<P: Into<path::PathBuf>>(mut self, dir: P) -> Self {
+        self.staging_dir = Some(dir.into());
+        self
+    }
+
+    /// Allow exchanging the root of a mounted file system.
+    ///
+    /// By default, this fails with `Error::MountPoint` before anything is changed. Most
+    /// platforms refuse to rename a mount point anyway.
+    pub fn allow_mount_points(mut self, allow: bool) -> Self {
+        self.policy.mount_points = allow;
+        self
+    }
+
+    /// Allow exchanging FIFOs, sockets and device nodes.
+    ///
+    /// By default, this fails with `Error::SpecialFile` before anything is changed.
+    pub fn allow_special_files(mut self, allow: bool) -> Self {
+        self.policy.special_files = allow;
+        self
+    }
+
+    /// Refuse paths that users other than their owners could modify.
+    ///
+    /// Before anything is changed, this fails with `Error::Insecure` if a path, or its parent
+    /// directory, can be written by its group or by others. A parent directory with the sticky
+    /// bit, like `/tmp`, is accepted for entries owned by the owner of the directory or by the
+    /// current user. With a `Rooted` backend that is `Rooted::secure` too, the checks are repeated
+    /// on the parent directories the exchange renames in. Paths whose owner the backend does not
+    /// know are always refused, so this refuses everything on Windows.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.policy.secure = secure;
+        self
+    }
+
+    /// Compare the digests of both paths before and after the exchange.
+    ///
+    /// The digests are computed right before the exchange, after the before hook. If the content
+    /// found at the paths after the exchange differs, the paths are exchanged back, and the error
+    /// is `Error::RolledBack` with `Error::ChecksumMismatch` inside. This reads both trees twice.
+    ///
+    /// See `digest`.
+    #[cfg(feature = "checksum")]
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.checksums = verify;
+        self
+    }
+
+    /// Give the content that is exchanged into each path the security labels of the object that
+    /// was there before.
+    ///
+    /// The labels of both paths, their SELinux contexts and POSIX ACLs (see `Labels`), are read
+    /// right before the exchange, after the before hook. Right after the exchange, each path gets
+    /// the labels that were read from it, and the relabel hook is called with the attributes that
+    /// changed. Only the objects at the paths themselves are relabeled, not the entries of
+    /// directories. If a label can't be set, the labels set so far are restored, the paths are
+    /// exchanged back, and the error is `Error::RolledBack` with `Error::Relabel` inside.
+    ///
+    /// When the paths are exchanged back later, for example because the after hook failed, their
+    /// labels are preserved the same way.
+    pub fn preserve_labels(mut self, preserve: bool) -> Self {
+        self.labels = preserve;
+        self
+    }
+
+    /// Call `hook` with a path and the names of the attributes that changed, after
+    /// `preserve_labels` relabeled the path.
+    ///
+    /// The hook is not called for a path whose labels did not have to change.
+    ///
+    /// ```no_run
+    /// let options = libxch::Options::new()
+    ///     .preserve_labels(true)
+    ///     .on_relabel(|path, changed| println!("relabeled {}: {}", path.display(), changed.join(", ")));
+    /// options.xch("/srv/www", "/home/build/www")?;
+    /// # Ok::<(), libxch::Error>(())
+    /// ```
+    pub fn on_relabel<F>(mut self, hook: F) -> Self
+        where F: Fn(&path::Path, &[String]) + Send + Sync + 'static
+    {
+        self.hooks.relabel = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with both paths right before they are exchanged.
+    ///
+    /// If the hook fails, nothing is exchanged and the error is returned as `Error::Hook`. The
+    /// hook is not called if both paths refer to the same object, or if the checks before the
+    /// exchange fail.
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    ///
+    /// let systemctl = |action: &str| -> Result<(), libxch::Error> {
+    ///     match Command::new("systemctl").args(&[action, "myapp"]).status()?.success() {
+    ///         true => Ok(()),
+    ///         false => Err(format!("could not {} myapp", action).into()),
+    ///     }
+    /// };
+    /// let options = libxch::Options::new()
+    ///     .on_before(move |_, _| systemctl("stop"))
+    ///     .on_after(move |_, _| systemctl("start"));
+    /// options.xch("/etc/myapp", "/etc/myapp.new")?;
+    /// # Ok::<(), libxch::Error>(())
+    /// ```
+    pub fn on_before<F>(mut self, hook: F) -> Self
+        where F: Fn(&path::Path, &path::Path) -> Result<()> + Send + Sync + 'static
+    {
+        self.hooks.before = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with both paths right after they were exchanged.
+    ///
+    /// If the hook fails, the error is returned as `Error::Hook` and the paths stay exchanged,
+    /// unless `revert_on_hook_failure` is set.
+    pub fn on_after<F>(mut self, hook: F) -> Self
+        where F: Fn(&path::Path, &path::Path) -> Result<()> + Send + Sync + 'static
+    {
+        self.hooks.after = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with both paths after an exchange of them was rolled back.
+    ///
+    /// This happens when the non-atomic fallback fails part way, when a rotation is reverted,
+    /// and when the after hook fails with `revert_on_hook_failure` set. The paths are already
+    /// restored when the hook is called, so it can't fail.
+    pub fn on_rollback<F>(mut self, hook: F) -> Self
+        where F: Fn(&path::Path, &path::Path) + Send + Sync + 'static
+    {
+        self.hooks.rollback = Some(Arc::new(hook));
+        self
+    }
+
+    /// Exchange the paths back if the after hook fails.
+    ///
+    /// The paths are exchanged back the same way they were exchanged. The error is then
+    /// `Error::RolledBack` with the `Error::Hook` inside.
+    pub fn revert_on_hook_failure(mut self, revert: bool) -> Self {
+        self.hooks.revert_on_failure = revert;
+        self
+    }
+
+    /// Exchange the content of two paths on the real file system.
+    ///
+    /// Returns how the paths were exchanged.
+    pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(&self, path1: A, path2: B) -> Result<Strategy> {
+        self.xch_with(&Native, path1, path2)
+    }
+
+    /// Exchange the content of two paths, using the given backend.
+    ///
+    /// Calls the hooks around the exchange.
+    pub fn xch_with<F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>>(&self, backend: &F, path1: A, path2: B) -> Result<Strategy> {
+        let path1 = path1.as_ref();
+        let path2 = path2.as_ref();
+
+        debug!("exchanging {} and {}", path1.display(), path2.display());
+        if !preflight::check(backend, &self.policy, path1, path2)? {
+            debug!("{} and {} are the same, nothing to do", path1.display(), path2.display());
+            return Ok(Strategy::Unchanged);
+        }
+        Hooks::run(&self.hooks.before, "before", path1, path2)?;
+        let check = self.content_check(backend, path1, path2)?;
+        let relabel = self.relabel_check(backend, path1, path2)?;
+        let check: Check = Box::new(move || {
+            check()?;
+            relabel()
+        });
+        let strategy = match self.exchange(backend, path1, path2, check) {
+            Ok(strategy) => strategy,
+            Err(e) => {
+                if let Error::RolledBack(_) = e {
+                    self.hooks.rolled_back(path1, path2);
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = Hooks::run(&self.hooks.after, "after", path1, path2) {
+            if !self.hooks.revert_on_failure {
+                return Err(e);
+            }
+            debug!("exchanging {} and {} back", path1.display(), path2.display());
+            if let Err(rollback) = self.exchange_back(backend, path1, path2) {
+                return Err(Error::ChainError(Box::new(rollback), Box::new(e)));
+            }
+            self.hooks.rolled_back(path1, path2);
+            return Err(Error::RolledBack(Box::new(e)));
+        }
+        Ok(strategy)
+    }
+
+    /// Exchange two paths that passed the checks, without calling any hooks.
+    ///
+    /// `check` is called right after the exchange. If it fails, the exchange is undone.
+    fn exchange<F: Backend>(&self, backend: &F, path1: &path::Path, path2: &path::Path, check: Check) -> Result<Strategy> {
+        match backend.exchange(path1, path2) {
+            Err(ref e) if self.non_atomic && e.kind() == ErrorKind::Unsupported => {
+                debug!("atomic exchange is not available ({}), falling back to non-atomic exchange", e);
+                non_atomic::xch(backend, self.staging_dir.as_ref().map(AsRef::as_ref), path1, path2, check)
+                    .map(|_| Strategy::NonAtomic)
+            }
+            Err(e) => Err(e),
+            Ok(()) => match check() {
+                Ok(()) => Ok(Strategy::Atomic),
+                Err(e) => {
+                    debug!("exchanging {} and {} back: {}", path1.display(), path2.display(), e);
+                    match backend.exchange(path1, path2) {
+                        Ok(()) => Err(Error::RolledBack(Box::new(e))),
+                        Err(rollback) => Err(Error::ChainError(Box::new(rollback), Box::new(e))),
+                    }
+                }
+            },
+        }
+    }
+
+    /// Exchange two paths again to undo an exchange, preserving their labels like the exchange did.
+    fn exchange_back<F: Backend>(&self, backend: &F, path1: &path::Path, path2: &path::Path) -> Result<Strategy> {
+        let relabel = self.relabel_check(backend, path1, path2)?;
+        self.exchange(backend, path1, path2, relabel)
+    }
+
+    /// Read the labels of both paths, and return a check that gives each path its labels back
+    /// after the exchange.
+    fn relabel_check<'a, F: Backend>(&self, backend: &'a F, path1: &'a path::Path, path2: &'a path::Path) -> Result<Check<'a>> {
+        if !self.labels {
+            return Ok(Box::new(|| Ok(())));
+        }
+        let (labels1, labels2) = (backend.labels(path1)?, backend.labels(path2)?);
+        let hook = self.hooks.relabel.clone();
+        Ok(Box::new(move || {
+            // After the exchange, each path holds the other object, which still has its old labels.
+            let relabel: Vec<_> = [(path1, &labels1, &labels2), (path2, &labels2, &labels1)].iter()
+                .map(|&(path, wanted, found)| (path, wanted, found, wanted.differences(found)))
+                .filter(|(_, _, _, changed)| !changed.is_empty())
+                .collect();
+            let mut done = Vec::new();
+            for &(path, wanted, found, ref changed) in &relabel {
+                for name in changed {
+                    trace!("setting {} of {}", name, path.display());
+                    if let Err(error) = backend.set_label(path, name, wanted.get(name)) {
+                        debug!("could not set {} of {}, restoring {} labels: {}", name, path.display(), done.len(), error);
+                        let err = Error::Relabel { path: path.to_path_buf(), error };
+                        for (path, name, value) in done.into_iter().rev() {
+                            if let Err(error) = backend.set_label(path, name, value) {
+                                let restore = Error::Relabel { path: path.to_path_buf(), error };
+                                return Err(Error::ChainError(Box::new(restore), Box::new(err)));
+                            }
+                        }
+                        return Err(err);
+                    }
+                    done.push((path, name, found.get(name)));
+                }
+            }
+            if let Some(ref hook) = hook {
+                for &(path, _, _, ref changed) in &relabel {
+                    hook(path, changed);
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    /// Compute the digests of both paths, and return a check that they were exchanged intact.
+    #[cfg(feature = "checksum")]
+    fn content_check<'a, F: Backend>(&self, backend: &'a F, path1: &'a path::Path, path2: &'a path::Path) -> Result<Check<'a>> {
+        if !self.checksums {
+            return Ok(Box::new(|| Ok(())));
+        }
+        let (digest1, digest2) = (backend.digest(path1)?, backend.digest(path2)?);
+        trace!("digests before the exchange: {} {}, {} {}", digest1, path1.display(), digest2, path2.display());
+        Ok(Box::new(move || {
+            for &(path, expected) in &[(path1, digest2), (path2, digest1)] {
+                let found = backend.digest(path)?;
+                if found != expected {
+                    debug!("digest of {} is {}, expected {}", path.display(), found, expected);
+                    return Err(Error::ChecksumMismatch(path.to_path_buf()));
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    fn content_check<'a, F: Backend>(&self, _backend: &'a F, _path1: &'a path::Path, _path2: &'a path::Path) -> Result<Check<'a>> {
+        Ok(Box::new(|| Ok(())))
+    }
+
+    /// Exchange two paths on the real file system, run `check`, and exchange them back if it fails.
+    ///
+    /// See `xch_verified_with`.
+    ///
+    /// ```no_run
+    /// let verification = libxch::Options::new().xch_verified("/srv/current", "/srv/next", |current, _| {
+    ///     match current.join("index.html").exists() {
+    ///         true => Ok(()),
+    ///         false => Err("index.html is missing".into()),
+    ///     }
+    /// })?;
+    /// if let Some(failure) = verification.failure {
+    ///     eprintln!("exchanged back: {}", failure);
+    /// }
+    /// # Ok::<(), libxch::Error>(())
+    /// ```
+    pub fn xch_verified<A, B, C>(&self, path1: A, path2: B, check: C) -> Result<Verification>
+        where A: AsRef<path::Path>, B: AsRef<path::Path>, C: FnOnce(&path::Path, &path::Path) -> Result<()>
+    {
+        self.xch_verified_with(&Native, path1, path2, check)
+    }
+
+    /// Exchange two paths, run `check`, and exchange them back if it fails, using the given backend.
+    ///
+    /// The check is called with both paths after the exchange and the after hook. If it fails,
+    /// the paths are exchanged back the same way they were exchanged, and the rollback hook is
+    /// called. A failed check is not an error: it is reported in `Verification::failure`.
+    ///
+    /// Fails if the exchange fails, or with `Error::ChainError` if exchanging back fails.
+    pub fn xch_verified_with<F, A, B, C>(&self, backend: &F, path1: A, path2: B, check: C) -> Result<Verification>
+        where F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>, C: FnOnce(&path::Path, &path::Path) -> Result<()>
+    {
+        let path1 = path1.as_ref();
+        let path2 = path2.as_ref();
+
+        let exchange = self.xch_with(backend, path1, path2)?;
+        let failure = match check(path1, path2) {
+            Ok(()) => {
+                return Ok(Verification {
+                    exchange,
+                    failure: None,
+                    revert: None,
+                })
+            }
+            Err(e) => e,
+        };
+        debug!("check failed, exchanging {} and {} back: {}", path1.display(), path2.display(), failure);
+        let revert = match exchange {
+            Strategy::Unchanged => Strategy::Unchanged,
+            _ => match self.exchange_back(backend, path1, path2) {
+                Ok(strategy) => {
+                    self.hooks.rolled_back(path1, path2);
+                    strategy
+                }
+                Err(rollback) => return Err(Error::ChainError(Box::new(rollback), Box::new(failure))),
+            },
+        };
+        Ok(Verification {
+            exchange,
+            failure: Some(failure),
+            revert: Some(revert),
+        })
+    }
+
+    /// Check if two paths could be exchanged with these options, without changing anything.
+    ///
+    /// Returns `Ok(false)` if both paths refer to the same object, so that an exchange would
+    /// not do anything. Fails if one path is inside the other, if the paths are on different
+    /// file systems, or if atomic exchange is required but not available.
+    pub fn check<A: AsRef<path::Path>, B: AsRef<path::Path>>(&self, path1: A, path2: B) -> Result<bool> {
+        self.check_with(&Native, path1, path2)
+    }
+
+    /// Check if two paths could be exchanged, using the given backend.
+    pub fn check_with<F: Backend, A: AsRef<path::Path>, B: AsRef<path::Path>>(&self, backend: &F, path1: A, path2: B) -> Result<bool> {
+        let path1 = path1.as_ref();
+        let path2 = path2.as_ref();
+
+        if !preflight::check(backend, &self.policy, path1, path2)? {
+            return Ok(false);
+        }
+        if let (Some((dev1, _)), Some((dev2, _))) = (backend.lookup(path1)?.id, backend.lookup(path2)?.id) {
+            if dev1 != dev2 {
+                return Err(format!("{} and {} are on different file systems", path1.display(), path2.display()).into());
+            }
+        }
+        if !self.non_atomic && !backend.supports_exchange() {
+            return Err(Error::NotImplemented);
+        }
+        Ok(true)
+    }
+
+    /// Rotate the content of the paths on the real file system.
+    ///
+    /// The first path gets the content of the second, the second the content of the third and so
+    /// on. The last path gets the content of the first.
+    ///
+    /// Returns the weakest strategy used for any of the single exchanges.
+    pub fn rotate<P: AsRef<path::Path>>(&self, paths: &[P]) -> Result<Strategy> {
+        self.rotate_with(&Native, paths)
+    }
+
+    /// Rotate the content of the paths, using the given backend.
+    ///
+    /// This exchanges neighbouring paths one after the other, so the rotation as a whole is never
+    /// atomic. If one exchange fails, the exchanges done so far are reverted. The hooks are
+    /// called around every single exchange, but not when reverting.
+    pub fn rotate_with<F: Backend, P: AsRef<path::Path>>(&self, backend: &F, paths: &[P]) -> Result<Strategy> {
+        let mut strategy = Strategy::Unchanged;
+        let mut changed = Vec::new();
+        for pair in paths.windows(2) {
+            match self.xch_with(backend, &pair[0], &pair[1]) {
+                Ok(Strategy::Unchanged) => (),
+                Ok(s) => {
+                    strategy = strategy.max(s);
+                    changed.push(pair);
+                }
+                Err(e) => {
+                    // A failed exchange that was already undone is rolled back as part of the rotation.
+                    let (e, rolled_back) = match e {
+                        Error::RolledBack(cause) => (*cause, true),
+                        e => (e, false),
+                    };
+                    debug!("rotation failed at {}, reverting {} earlier exchanges: {}", pair[0].as_ref().display(), changed.len(), e);
+                    let reverted = !changed.is_empty();
+                    for done in changed.into_iter().rev() {
+                        let (path1, path2) = (done[0].as_ref(), done[1].as_ref());
+                        if let Err(rollback) = self.exchange_back(backend, path1, path2) {
+                            return Err(Error::ChainError(Box::new(rollback), Box::new(e)));
+                        }
+                        self.hooks.rolled_back(path1, path2);
+                    }
+                    return Err(if reverted || rolled_back { Error::RolledBack(Box::new(e)) } else { e });
+                }
+            }
+        }
+        Ok(strategy)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs,
+    io,
+    path,
+    time,
+};
+
+use crate::{
+    error::Result,
+    non_atomic::TEMP_PREFIX,
+    platform,
+};
+
+/// Suffix of the sidecar file describing a temporary entry.
+pub const SIDECAR_SUFFIX: &str = ".origin";
+
+/// A temporary entry left behind by an interrupted non-atomic exchange.
+///
+/// The non-atomic fallback moves the first path to a temporary name starting with `.xch-tmp-`.
+/// If the process is killed, or the rollback fails, the entry stays there. Next to it, a sidecar
+/// file records where it came from, unless the sidecar could not be written.
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    /// The temporary entry.
+    pub path: path::PathBuf,
+    /// The sidecar file describing the entry, if there is one.
+    pub sidecar: Option<path::PathBuf>,
+    /// The path the entry was moved away from, if known.
+    pub origin: Option<path::PathBuf>,
+    /// The path the entry was supposed to be moved to, if known.
+    pub target: Option<path::PathBuf>,
+    /// Time since the exchange started, or since the last modification of the entry if there is
+    /// no sidecar.
+    pub age: Option<time::Duration>,
+    /// Total size in bytes of the entry, including everything inside it for directories.
+    pub size: u64,
+}
+
+impl Orphan {
+    /// Move the entry back to its original path.
+    ///
+    /// Fails if the original path is unknown, or if something else already exists there.
+    pub fn restore(&self) -> Result<()> {
+        let origin = self.origin.as_ref()
+            .ok_or_else(|| format!("Original path of {} is unknown", self.path.display()))?;
+        self.restore_to(origin)
+    }
+
+    /// Move the entry to the given path, which must not exist.
+    pub fn restore_to<P: AsRef<path::Path>>(&self, dest: P) -> Result<()> {
+        platform::rename_noreplace(&self.path, dest.as_ref())?;
+        self.remove_sidecar()
+    }
+
+    /// Delete the entry, including everything inside it, and its sidecar.
+    pub fn remove(&self) -> Result<()> {
+        let meta = fs::symlink_metadata(&self.path)?;
+        if meta.is_dir() {
+            fs::remove_dir_all(&self.path)?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.remove_sidecar()
+    }
+
+    fn remove_sidecar(&self) -> Result<()> {
+        match self.sidecar {
+            Some(ref sidecar) => fs::remove_file(sidecar).map_err(Into::into),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Find temporary entries left behind by interrupted non-atomic exchanges in `dir`.
+///
+/// Only the directory itself is scanned, not its subdirectories. Sidecar files without a
+/// matching entry are ignored.
+pub fn scan_orphans<P: AsRef<path::Path>>(dir: P) -> Result<Vec<Orphan>> {
+    let mut orphans = Vec::new();
+    for entry in fs::read_dir(dir.as_ref())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(TEMP_PREFIX) || name.ends_with(SIDECAR_SUFFIX) {
+            continue;
+        }
+
+        let path = entry.path();
+        let sidecar = sidecar_path(&path);
+        let (sidecar, origin, target, age) = match fs::read(&sidecar) {
+            Ok(content) => {
+                let (origin, target) = parse_sidecar(&content);
+                let age = fs::symlink_metadata(&sidecar)?.modified().ok().and_then(|t| t.elapsed().ok());
+                (Some(sidecar), origin, target, age)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let age = entry.metadata()?.modified().ok().and_then(|t| t.elapsed().ok());
+                (None, None, None, age)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        orphans.push(Orphan {
+            size: disk_size(&path)?,
+            path,
+            sidecar,
+            origin,
+            target,
+            age,
+        });
+    }
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(orphans)
+}
+
+/// The path of the sidecar file for the given temporary entry.
+pub fn sidecar_path(temp: &path::Path) -> path::PathBuf {
+    let mut name = temp.file_name().map(ToOwned::to_owned).unwrap_or_default();
+    name.push(SIDECAR_SUFFIX);
+    temp.with_file_name(name)
+}
+
+/// Encode the content of a sidecar file.
+///
+/// Returns `None` if a path can't be stored, i.e. if it contains a newline.
+pub fn sidecar_content(origin: &path::Path, target: &path::Path) -> Option<Vec<u8>> {
+    let mut content = Vec::new();
+    for (key, path) in &[("origin", origin), ("target", target)] {
+        let bytes = path_to_bytes(path);
+        if bytes.contains(&b'\n') {
+            return None;
+        }
+        content.extend_from_slice(key.as_bytes());
+        content.push(b'=');
+        content.extend_from_slice(&bytes);
+        content.push(b'\n');
+    }
+    Some(content)
+}
+
+fn parse_sidecar(content: &[u8]) -> (Option<path::PathBuf>, Option<path::PathBuf>) {
+    let mut origin = None;
+    let mut target = None;
+    for line in content.split(|&b| b == b'\n') {
+        if let Some(value) = line.strip_prefix(b"origin=") {
+            origin = Some(bytes_to_path(value));
+        } else if let Some(value) = line.strip_prefix(b"target=") {
+            target = Some(bytes_to_path(value));
+        }
+    }
+    (origin, target)
+}
+
+/// Sum up the size of all files below `path`, without following symbolic links.
+fn disk_size(path: &path::Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += disk_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> path::PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    ::std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &path::Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> path::PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
@@ -1,8 +1,104 @@
+#define _GNU_SOURCE
+#include <errno.h>
 #include <fcntl.h>
+#include <stdio.h>
 #include <unistd.h>
+#include <sys/inotify.h>
+#include <sys/ioctl.h>
 #include <sys/syscall.h>
+#include <sys/xattr.h>
+
+#ifndef RENAME_NOREPLACE
+#define RENAME_NOREPLACE 1
+#endif
+#ifndef RENAME_EXCHANGE
+#define RENAME_EXCHANGE 2
+#endif
+#ifndef FICLONE
+#define FICLONE _IOW(0x94, 9, int)
+#endif
+#ifndef SYS_openat2
+#define SYS_openat2 437
+#endif
+
+/* struct open_how from <linux/openat2.h>, which older headers lack. */
+struct xch_open_how {
+    unsigned long long flags;
+    unsigned long long mode;
+    unsigned long long resolve;
+};
 
 long linux_xch_syscall(const char* path1, const char* path2) {
-    const int RENAME_EXCHANGE = 2;
     return syscall(SYS_renameat2, AT_FDCWD, path1, AT_FDCWD, path2, RENAME_EXCHANGE);
 }
+
+long linux_rename_noreplace(const char* from, const char* to) {
+    return syscall(SYS_renameat2, AT_FDCWD, from, AT_FDCWD, to, RENAME_NOREPLACE);
+}
+
+long linux_exchange_at(int dirfd1, const char* name1, int dirfd2, const char* name2) {
+    return syscall(SYS_renameat2, dirfd1, name1, dirfd2, name2, RENAME_EXCHANGE);
+}
+
+long linux_rename_at(int dirfd1, const char* name1, int dirfd2, const char* name2, int noreplace) {
+    return syscall(SYS_renameat2, dirfd1, name1, dirfd2, name2, noreplace ? RENAME_NOREPLACE : 0);
+}
+
+int linux_unlink_at(int dirfd, const char* name) {
+    return unlinkat(dirfd, name, 0);
+}
+
+int linux_open_beneath(int dirfd, const char* path, int directory, unsigned long long resolve) {
+    struct xch_open_how how = { O_PATH | O_CLOEXEC, 0, resolve };
+    how.flags |= directory ? O_DIRECTORY : O_NOFOLLOW;
+    return syscall(SYS_openat2, dirfd, path, &how, sizeof(how));
+}
+
+int linux_open_nofollow(int dirfd, const char* name) {
+    return openat(dirfd, name, O_PATH | O_NOFOLLOW | O_CLOEXEC);
+}
+
+long linux_getxattr(const char* path, const char* name, void* value, size_t size) {
+    return lgetxattr(path, name, value, size);
+}
+
+int linux_setxattr(const char* path, const char* name, const void* value, size_t size) {
+    return lsetxattr(path, name, value, size, 0);
+}
+
+int linux_removexattr(const char* path, const char* name) {
+    return lremovexattr(path, name);
+}
+
+long linux_copy_file_range(int fd_in, long long off_in, int fd_out, long long off_out, size_t len) {
+    return syscall(SYS_copy_file_range, fd_in, &off_in, fd_out, &off_out, len, 0);
+}
+
+int linux_ficlone(int fd_in, int fd_out) {
+    return ioctl(fd_out, FICLONE, fd_in);
+}
+
+int linux_inotify_init(void) {
+    return inotify_init1(IN_CLOEXEC);
+}
+
+int linux_inotify_watch_dir(int fd, const char* dir) {
+    return inotify_add_watch(fd, dir, IN_CREATE | IN_MOVED_TO | IN_ONLYDIR);
+}
+
+int linux_open_tmpfile(const char* dir) {
+    return open(dir, O_TMPFILE | O_RDWR | O_CLOEXEC, 0666);
+}
+
+long linux_link_fd(int fd, const char* to) {
+    char proc_path[64];
+    if (linkat(fd, "", AT_FDCWD, to, AT_EMPTY_PATH) == 0) {
+        return 0;
+    }
+    /* AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH, linking through /proc does not. */
+    if (errno != ENOENT && errno != EPERM) {
+        return -1;
+    }
+    snprintf(proc_path, sizeof(proc_path), "/proc/self/fd/%d", fd);
+    return linkat(AT_FDCWD, proc_path, AT_FDCWD, to, AT_SYMLINK_FOLLOW);
+}
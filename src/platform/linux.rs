@@ -1,17 +1,39 @@
-use errno;
 use std::{
     error,
     fmt,
+    fs,
+    io,
     path,
     ffi,
     os,
 };
 
-use crate::error::Result;
-extern {
+use crate::{
+    cloning::CloneMethod,
+    error::{ErrorKind, Result},
+};
+extern "C" {
     fn linux_xch_syscall(path1: *const os::raw::c_char, path2: *const os::raw::c_char) -> os::raw::c_long;
+    fn linux_rename_noreplace(from: *const os::raw::c_char, to: *const os::raw::c_char) -> os::raw::c_long;
+    fn linux_exchange_at(dirfd1: os::raw::c_int, name1: *const os::raw::c_char, dirfd2: os::raw::c_int, name2: *const os::raw::c_char) -> os::raw::c_long;
+    fn linux_rename_at(dirfd1: os::raw::c_int, name1: *const os::raw::c_char, dirfd2: os::raw::c_int, name2: *const os::raw::c_char, noreplace: os::raw::c_int) -> os::raw::c_long;
+    fn linux_unlink_at(dirfd: os::raw::c_int, name: *const os::raw::c_char) -> os::raw::c_int;
+    fn linux_open_nofollow(dirfd: os::raw::c_int, name: *const os::raw::c_char) -> os::raw::c_int;
+    fn linux_open_beneath(dirfd: os::raw::c_int, path: *const os::raw::c_char, directory: os::raw::c_int, resolve: os::raw::c_ulonglong) -> os::raw::c_int;
+    fn linux_getxattr(path: *const os::raw::c_char, name: *const os::raw::c_char, value: *mut os::raw::c_void, size: usize) -> os::raw::c_long;
+    fn linux_setxattr(path: *const os::raw::c_char, name: *const os::raw::c_char, value: *const os::raw::c_void, size: usize) -> os::raw::c_int;
+    fn linux_removexattr(path: *const os::raw::c_char, name: *const os::raw::c_char) -> os::raw::c_int;
+    fn linux_copy_file_range(fd_in: os::raw::c_int, off_in: os::raw::c_longlong, fd_out: os::raw::c_int, off_out: os::raw::c_longlong, len: usize) -> os::raw::c_long;
+    fn linux_ficlone(fd_in: os::raw::c_int, fd_out: os::raw::c_int) -> os::raw::c_int;
+    fn linux_inotify_init() -> os::raw::c_int;
+    fn linux_inotify_watch_dir(fd: os::raw::c_int, dir: *const os::raw::c_char) -> os::raw::c_int;
+    fn linux_open_tmpfile(dir: *const os::raw::c_char) -> os::raw::c_int;
+    fn linux_link_fd(fd: os::raw::c_int, to: *const os::raw::c_char) -> os::raw::c_long;
 }
 
+/// Atomic exchange is available through `renameat2`.
+pub const SUPPORTS_EXCHANGE: bool = true;
+
 pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> Result<()> {
     use std::os::unix::ffi::OsStrExt;
 
@@ -22,6 +44,336 @@ pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> Re
     let ret = unsafe {
         linux_xch_syscall(path1.as_ptr(), path2.as_ptr())
     };
+    let res = check_syscall(ret);
+    trace!("renameat2({:?}, {:?}, RENAME_EXCHANGE) = {}{}", path1, path2, ret, errno_suffix(&res));
+    res
+}
+
+/// Rename without replacing an existing entry, using `renameat2` with `RENAME_NOREPLACE`.
+///
+/// Falls back to a check followed by a rename on kernels or file systems without support for it.
+pub fn rename_noreplace(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_from = ffi::CString::new(from.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let c_to = ffi::CString::new(to.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let ret = unsafe {
+        linux_rename_noreplace(c_from.as_ptr(), c_to.as_ptr())
+    };
+    if ret == 0 {
+        trace!("renameat2({:?}, {:?}, RENAME_NOREPLACE) = 0", c_from, c_to);
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    trace!("renameat2({:?}, {:?}, RENAME_NOREPLACE) = {} ({})", c_from, c_to, ret, err);
+    match err.raw_os_error() {
+        Some(ENOSYS) | Some(EINVAL) => {
+            debug!("RENAME_NOREPLACE is not supported, checking for {} before renaming", to.display());
+            super::rename_noreplace_racy(from, to)
+        }
+        _ => Err(err),
+    }
+}
+
+/// Scope `..` and absolute symbolic links to the starting directory, see `openat2(2)`.
+pub const RESOLVE_IN_ROOT: u64 = 0x10;
+/// Refuse to follow `/proc/<pid>/fd` and similar magic links.
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+/// Refuse to follow any symbolic link.
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+/// Refuse to leave the starting directory, with `..` or absolute paths and symbolic links.
+pub const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Open `path` relative to the directory `dir` with `openat2`, as an `O_PATH` file descriptor.
+///
+/// With `directory`, the object has to be a directory, and a final symbolic link is followed.
+/// Otherwise, a final symbolic link is opened itself. `resolve` is a combination of the
+/// `RESOLVE_*` flags. Fails with `ENOSYS` on kernels before 5.6.
+pub fn open_beneath(dir: &fs::File, path: &path::Path, directory: bool, resolve: u64) -> io::Result<fs::File> {
+    use std::os::unix::{ffi::OsStrExt, io::{AsRawFd, FromRawFd}};
+
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let fd = unsafe { linux_open_beneath(dir.as_raw_fd(), c_path.as_ptr(), directory as os::raw::c_int, resolve) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        trace!("openat2({}, {:?}, {:#x}) = {} ({})", dir.as_raw_fd(), c_path, resolve, fd, err);
+        return Err(err);
+    }
+    trace!("openat2({}, {:?}, {:#x}) = {}", dir.as_raw_fd(), c_path, resolve, fd);
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Open the entry `name` in the directory `dir` as an `O_PATH` file descriptor, with `openat`.
+///
+/// A symbolic link is opened itself. Works on all kernels, unlike `open_beneath`.
+pub fn open_nofollow(dir: &fs::File, name: &ffi::OsStr) -> io::Result<fs::File> {
+    use std::os::unix::{ffi::OsStrExt, io::{AsRawFd, FromRawFd}};
+
+    let c_name = ffi::CString::new(name.as_bytes()).expect("path cannot contain null byte");
+    let fd = unsafe { linux_open_nofollow(dir.as_raw_fd(), c_name.as_ptr()) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        trace!("openat({}, {:?}, O_PATH | O_NOFOLLOW) = {} ({})", dir.as_raw_fd(), c_name, fd, err);
+        return Err(err);
+    }
+    trace!("openat({}, {:?}, O_PATH | O_NOFOLLOW) = {}", dir.as_raw_fd(), c_name, fd);
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Atomically exchange the entries `name1` in `dir1` and `name2` in `dir2`.
+pub fn exchange_at(dir1: &fs::File, name1: &ffi::OsStr, dir2: &fs::File, name2: &ffi::OsStr) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    let c_name1 = ffi::CString::new(name1.as_bytes()).expect("path cannot contain null byte");
+    let c_name2 = ffi::CString::new(name2.as_bytes()).expect("path cannot contain null byte");
+    let ret = unsafe {
+        linux_exchange_at(dir1.as_raw_fd(), c_name1.as_ptr(), dir2.as_raw_fd(), c_name2.as_ptr())
+    };
+    let res = check_syscall(ret);
+    trace!("renameat2({}, {:?}, {}, {:?}, RENAME_EXCHANGE) = {}{}", dir1.as_raw_fd(), c_name1, dir2.as_raw_fd(), c_name2, ret, errno_suffix(&res));
+    res
+}
+
+/// Rename the entry `from` in `from_dir` to `to` in `to_dir`, optionally without replacing `to`.
+pub fn rename_at(from_dir: &fs::File, from: &ffi::OsStr, to_dir: &fs::File, to: &ffi::OsStr, noreplace: bool) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    let c_from = ffi::CString::new(from.as_bytes()).expect("path cannot contain null byte");
+    let c_to = ffi::CString::new(to.as_bytes()).expect("path cannot contain null byte");
+    let flags = if noreplace { "RENAME_NOREPLACE" } else { "0" };
+    let ret = unsafe {
+        linux_rename_at(from_dir.as_raw_fd(), c_from.as_ptr(), to_dir.as_raw_fd(), c_to.as_ptr(), noreplace as os::raw::c_int)
+    };
+    if ret == 0 {
+        trace!("renameat2({}, {:?}, {}, {:?}, {}) = 0", from_dir.as_raw_fd(), c_from, to_dir.as_raw_fd(), c_to, flags);
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    trace!("renameat2({}, {:?}, {}, {:?}, {}) = {} ({})", from_dir.as_raw_fd(), c_from, to_dir.as_raw_fd(), c_to, flags, ret, err);
+    Err(err)
+}
+
+/// Remove the entry `name` in `dir`, which must not be a directory.
+pub fn unlink_at(dir: &fs::File, name: &ffi::OsStr) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    let c_name = ffi::CString::new(name.as_bytes()).expect("path cannot contain null byte");
+    let ret = unsafe { linux_unlink_at(dir.as_raw_fd(), c_name.as_ptr()) };
+    if ret == 0 {
+        trace!("unlinkat({}, {:?}, 0) = 0", dir.as_raw_fd(), c_name);
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    trace!("unlinkat({}, {:?}, 0) = {} ({})", dir.as_raw_fd(), c_name, ret, err);
+    Err(err)
+}
+
+/// Read the extended attribute `name` of `path`, without following a final symbolic link.
+///
+/// Returns `None` if the attribute is not set, or if the file system does not support it.
+pub fn get_xattr(path: &path::Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let c_name = ffi::CString::new(name).expect("attribute name cannot contain null byte");
+    let mut value = Vec::new();
+    loop {
+        // With an empty buffer, lgetxattr returns the size of the value.
+        let ret = unsafe { linux_getxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr() as *mut os::raw::c_void, value.len()) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            trace!("lgetxattr({:?}, {:?}) = {} ({})", c_path, c_name, ret, err);
+            return match err.raw_os_error() {
+                Some(ENODATA) | Some(EOPNOTSUPP) => Ok(None),
+                // The value grew since its size was read.
+                Some(ERANGE) => {
+                    value.clear();
+                    continue;
+                }
+                _ => Err(err),
+            };
+        }
+        if value.is_empty() && ret > 0 {
+            value.resize(ret as usize, 0);
+            continue;
+        }
+        trace!("lgetxattr({:?}, {:?}) = {}", c_path, c_name, ret);
+        value.truncate(ret as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Set the extended attribute `name` of `path` to `value`, or remove it for `None`, without
+/// following a final symbolic link.
+///
+/// Removing an attribute that is not set succeeds.
+pub fn set_xattr(path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let c_name = ffi::CString::new(name).expect("attribute name cannot contain null byte");
+    let ret = match value {
+        Some(value) => unsafe { linux_setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const os::raw::c_void, value.len()) },
+        None => unsafe { linux_removexattr(c_path.as_ptr(), c_name.as_ptr()) },
+    };
+    let call = if value.is_some() { "lsetxattr" } else { "lremovexattr" };
+    if ret == 0 {
+        trace!("{}({:?}, {:?}) = 0", call, c_path, c_name);
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    trace!("{}({:?}, {:?}) = {} ({})", call, c_path, c_name, ret, err);
+    match err.raw_os_error() {
+        Some(ENODATA) | Some(EOPNOTSUPP) if value.is_none() => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Replace the content of `to` with the `len` bytes of `from`, sharing the data blocks if possible.
+///
+/// Tries the `FICLONE` ioctl first, which shares all blocks on copy-on-write file systems like
+/// btrfs and XFS. Otherwise falls back to `copy_file_range`, and to reading and writing through
+/// a buffer if that is not supported either, for example between different file systems. The
+/// file positions are not changed.
+pub fn clone_contents(from: &fs::File, to: &fs::File, len: u64) -> io::Result<CloneMethod> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { linux_ficlone(from.as_raw_fd(), to.as_raw_fd()) };
+    if ret == 0 {
+        trace!("ioctl({}, FICLONE, {}) = 0", to.as_raw_fd(), from.as_raw_fd());
+        return Ok(CloneMethod::Reflink);
+    }
+    let err = io::Error::last_os_error();
+    trace!("ioctl({}, FICLONE, {}) = {} ({})", to.as_raw_fd(), from.as_raw_fd(), ret, err);
+    match err.raw_os_error() {
+        Some(EXDEV) | Some(EINVAL) | Some(ENOTTY) | Some(EOPNOTSUPP) => copy_range(from, to, len),
+        _ => Err(err),
+    }
+}
+
+fn copy_range(from: &fs::File, to: &fs::File, len: u64) -> io::Result<CloneMethod> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = 0;
+    while offset < len {
+        let chunk = ::std::cmp::min(len - offset, 1 << 30) as usize;
+        let ret = unsafe {
+            linux_copy_file_range(from.as_raw_fd(), offset as os::raw::c_longlong, to.as_raw_fd(), offset as os::raw::c_longlong, chunk)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            trace!("copy_file_range({}, {}, {}) = {} ({})", from.as_raw_fd(), to.as_raw_fd(), chunk, ret, err);
+            return match err.raw_os_error() {
+                Some(EXDEV) | Some(ENOSYS) | Some(EINVAL) | Some(EOPNOTSUPP) => {
+                    debug!("copy_file_range is not supported, copying through a buffer");
+                    copy_buffered(from, to, offset, len).map(|_| CloneMethod::Copy)
+                }
+                _ => Err(err),
+            };
+        }
+        if ret == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while copying"));
+        }
+        offset += ret as u64;
+    }
+    Ok(CloneMethod::CopyRange)
+}
+
+fn copy_buffered(from: &fs::File, to: &fs::File, mut offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buffer = vec![0; 64 * 1024];
+    while offset < len {
+        let chunk = ::std::cmp::min(len - offset, buffer.len() as u64) as usize;
+        let read = from.read_at(&mut buffer[..chunk], offset)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while copying"));
+        }
+        to.write_all_at(&buffer[..read], offset)?;
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+/// Create an unnamed, temporary file in `dir` with `O_TMPFILE`.
+pub fn open_tmpfile(dir: &path::Path) -> io::Result<fs::File> {
+    use std::os::unix::{ffi::OsStrExt, io::FromRawFd};
+
+    let c_dir = ffi::CString::new(dir.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let fd = unsafe { linux_open_tmpfile(c_dir.as_ptr()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    trace!("open({:?}, O_TMPFILE) = {}", c_dir, fd);
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Create an inotify instance. Reading from it blocks until an event arrives.
+pub fn inotify_init() -> io::Result<fs::File> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { linux_inotify_init() };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Watch the directory `dir` for new entries, created or moved there.
+pub fn inotify_watch_dir(inotify: &fs::File, dir: &path::Path) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    let c_dir = ffi::CString::new(dir.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let wd = unsafe { linux_inotify_watch_dir(inotify.as_raw_fd(), c_dir.as_ptr()) };
+    trace!("inotify_add_watch({}, {:?}) = {}", inotify.as_raw_fd(), c_dir, wd);
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Give an open file without directory entry, created by `open_tmpfile`, the name `to`.
+///
+/// Fails if `to` already exists.
+pub fn link_fd(file: &fs::File, to: &path::Path) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    let c_to = ffi::CString::new(to.as_os_str().as_bytes()).expect("path cannot contain null byte");
+    let ret = unsafe { linux_link_fd(file.as_raw_fd(), c_to.as_ptr()) };
+    if ret == 0 {
+        trace!("linkat({}, {:?}) = 0", file.as_raw_fd(), c_to);
+        Ok(())
+    } else {
+        let err = io::Error::last_os_error();
+        trace!("linkat({}, {:?}) = {} ({})", file.as_raw_fd(), c_to, ret, err);
+        Err(err)
+    }
+}
+
+/// Check if an error of `open_tmpfile` means that the file system does not support `O_TMPFILE`.
+pub fn is_tmpfile_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(EOPNOTSUPP) | Some(EISDIR) | Some(EINVAL))
+}
+
+const EISDIR: i32 = 21;
+const EXDEV: i32 = 18;
+const ENOTTY: i32 = 25;
+const ERANGE: i32 = 34;
+const ENODATA: i32 = 61;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+const EOPNOTSUPP: i32 = 95;
+
+/// Describe the error of a syscall result for logging.
+fn errno_suffix(res: &Result<()>) -> String {
+    match *res {
+        Ok(()) => String::new(),
+        Err(ref e) => format!(" ({})", e),
+    }
+}
+
+/// Turn the return value of a syscall into a result, reading `errno` on failure.
+fn check_syscall(ret: os::raw::c_long) -> Result<()> {
     if ret == 0 {
         Ok(())
     } else {
@@ -32,6 +384,29 @@ pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(path1: A, path2: B) -> Re
 #[derive(Debug)]
 pub struct PlatformError(errno::Errno);
 
+impl PlatformError {
+    /// The error for the given errno value.
+    #[cfg(feature = "testing")]
+    pub fn from_raw_os_error(code: i32) -> PlatformError {
+        PlatformError(errno::Errno(code))
+    }
+
+    /// The errno value of this error.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        Some(self.0 .0)
+    }
+
+    /// The category of this error.
+    ///
+    /// `renameat2` reports file systems without support for `RENAME_EXCHANGE` with `EINVAL`.
+    pub fn kind(&self) -> ErrorKind {
+        match self.0 .0 {
+            EINVAL | ENOSYS | EOPNOTSUPP => ErrorKind::Unsupported,
+            code => ErrorKind::from_io(&io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
 impl fmt::Display for PlatformError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "{}", self.0)
@@ -43,3 +418,22 @@ impl error::Error for PlatformError {
         "OSError"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn test_check_syscall_errno() {
+        for &code in &[1, 13, 16, 18, 22, 38] {
+            errno::set_errno(errno::Errno(code));
+            match check_syscall(-1) {
+                Err(Error::PlatformError(e)) => assert_eq!(e.raw_os_error(), Some(code)),
+                other => panic!("unexpected result for errno {}: {:?}", code, other),
+            }
+        }
+        errno::set_errno(errno::Errno(22));
+        assert!(check_syscall(0).is_ok());
+    }
+}
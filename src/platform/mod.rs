@@ -2,6 +2,44 @@
 #[cfg_attr(target_os = "windows", path="windows.rs")]
 #[cfg_attr(target_os = "linux", path="linux.rs")]
 #[cfg_attr(all(not(target_os = "windows"), not(target_os = "linux")), path="not_implemented.rs")]
-mod platform;
+mod imp;
 
-pub use self::platform::{xch, PlatformError};
+pub use self::imp::{xch, rename_noreplace, PlatformError, SUPPORTS_EXCHANGE};
+#[cfg(target_os = "linux")]
+pub use self::imp::{clone_contents, exchange_at, get_xattr, inotify_init, inotify_watch_dir, is_tmpfile_unsupported, link_fd, open_beneath, open_nofollow, open_tmpfile, rename_at, set_xattr, unlink_at};
+#[cfg(target_os = "linux")]
+pub use self::imp::{RESOLVE_BENEATH, RESOLVE_IN_ROOT, RESOLVE_NO_MAGICLINKS, RESOLVE_NO_SYMLINKS};
+
+#[cfg(not(target_os = "windows"))]
+use std::{
+    fs,
+    io,
+    path,
+};
+
+/// The effective user ID of this process.
+#[cfg(unix)]
+pub fn effective_uid() -> Option<u32> {
+    extern "C" {
+        fn geteuid() -> u32;
+    }
+    Some(unsafe { geteuid() })
+}
+
+#[cfg(not(unix))]
+pub fn effective_uid() -> Option<u32> {
+    None
+}
+
+/// Rename without replacing an existing entry, for platforms without native support.
+///
+/// There is a short window between the check and the rename, in which a new entry at `to` would
+/// still be replaced.
+#[cfg(not(target_os = "windows"))]
+fn rename_noreplace_racy(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    match fs::symlink_metadata(to) {
+        Ok(_) => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => fs::rename(from, to),
+        Err(e) => Err(e),
+    }
+}
@@ -1,17 +1,38 @@
-use crate::error::{Error, Result};
+use crate::error::{Error, ErrorKind, Result};
 use std::{
     error,
     fmt,
+    io,
     path,
 };
 
+/// There is no atomic exchange on this platform.
+pub const SUPPORTS_EXCHANGE: bool = false;
+
 pub fn xch<A: AsRef<path::Path>, B: AsRef<path::Path>>(_path1: A, _path2: B) -> Result<()> {
     Err(Error::NotImplemented)
 }
 
+/// Rename without replacing an existing entry.
+pub fn rename_noreplace(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    super::rename_noreplace_racy(from, to)
+}
+
 #[derive(Debug)]
 pub struct PlatformError;
 
+impl PlatformError {
+    /// There is never an OS error code for this platform.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        None
+    }
+
+    /// The category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Unsupported
+    }
+}
+
 impl fmt::Display for PlatformError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "GenericPlatformError")
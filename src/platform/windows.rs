@@ -5,13 +5,17 @@ use std::{
     error,
     fmt,
     fs,
+    io,
     mem,
     path,
     ptr::null_mut,
     slice
 };
 
-use crate::error::{Error, Result};
+use crate::error::{Error, ErrorKind, Result};
+
+/// Atomic exchange is available through Transactional NTFS.
+pub const SUPPORTS_EXCHANGE: bool = true;
 
 /// Exchange two paths on a Windows machine.
 ///
@@ -71,6 +75,7 @@ impl Transaction {
     /// On success, returns nothing
     fn commit(self) -> Result<()> {
         let res = as_win_error(unsafe { winapi::um::ktmw32::CommitTransaction(self.0) });
+        trace!("CommitTransaction() = {:?}", res);
         if let Err(e) = res {
             self.rollback()?;
             Err(e)
@@ -99,9 +104,11 @@ impl Transaction {
         let to_encoded = to_wide_str(to.as_ref());
 
         let handle = self.0;
-        self.ok_or_rollback(as_win_error(unsafe {
+        let res = as_win_error(unsafe {
             winapi::um::winbase::MoveFileTransactedW(from_encoded.as_ptr(), to_encoded.as_ptr(), None, null_mut(), 0, handle)
-        }))
+        });
+        trace!("MoveFileTransactedW({}, {}) = {:?}", from.as_ref().display(), to.as_ref().display(), res);
+        self.ok_or_rollback(res)
     }
 
     /// Causes a rollback if the provided result is Err().
@@ -110,6 +117,7 @@ impl Transaction {
     /// record more changes.
     fn ok_or_rollback(self, api_res: Result<()>) -> Result<Self> {
         if let Err(e) = api_res {
+            debug!("rolling back transaction after error: {}", e);
             self.rollback()?;
             Err(e)
         } else {
@@ -126,9 +134,11 @@ impl Transaction {
         let from_encoded = to_wide_str(to_delete.as_ref());
 
         let handle = self.0;
-        self.ok_or_rollback(as_win_error(unsafe {
+        let res = as_win_error(unsafe {
             winapi::um::winbase::DeleteFileTransactedW(from_encoded.as_ptr(), handle)
-        }))
+        });
+        trace!("DeleteFileTransactedW({}) = {:?}", to_delete.as_ref().display(), res);
+        self.ok_or_rollback(res)
     }
 }
 
@@ -224,12 +234,44 @@ fn get_last_error() -> PlatformError {
     PlatformError(error, msg)
 }
 
+/// Rename without replacing an existing entry, using `MoveFileExW` without
+/// `MOVEFILE_REPLACE_EXISTING`.
+///
+/// Fails with `io::ErrorKind::AlreadyExists` if `to` exists, checked by the system in the same
+/// operation.
+pub fn rename_noreplace(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    let from_encoded = to_wide_str(from);
+    let to_encoded = to_wide_str(to);
+    let res = unsafe {
+        winapi::um::winbase::MoveFileExW(from_encoded.as_ptr(), to_encoded.as_ptr(), 0)
+    };
+    if res != winapi::shared::minwindef::FALSE {
+        trace!("MoveFileExW({}, {}, 0) = ok", from.display(), to.display());
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    trace!("MoveFileExW({}, {}, 0) = {}", from.display(), to.display(), err);
+    Err(err)
+}
+
 /// Represents a windows error
 ///
 /// This stores the error code as well as an error message generated by Windows.
 #[derive(Debug)]
 pub struct PlatformError(winapi::shared::minwindef::DWORD, String);
 
+impl PlatformError {
+    /// The Windows error code of this error.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        Some(self.0 as i32)
+    }
+
+    /// The category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::from_io(&io::Error::from_raw_os_error(self.0 as i32))
+    }
+}
+
 impl fmt::Display for PlatformError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Windows Error [{}]: {}", self.0, self.1)
@@ -0,0 +1,84 @@
+use std::path;
+
+use crate::{
+    backend::{Backend, Entry, FileType},
+    error::{Error, Result},
+    platform,
+};
+
+/// Which kinds of objects may be exchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Policy {
+    /// Allow the root of a mounted file system.
+    pub mount_points: bool,
+    /// Allow FIFOs, sockets and device nodes.
+    pub special_files: bool,
+    /// Refuse paths that users other than their owners can modify.
+    pub secure: bool,
+}
+
+impl Policy {
+    /// Fail if the entry at the user-provided `path` is not allowed.
+    fn allows(&self, path: &path::Path, entry: &Entry) -> Result<()> {
+        if entry.mount_point && !self.mount_points {
+            return Err(Error::MountPoint(path.to_path_buf()));
+        }
+        if entry.file_type.is_special() && !self.special_files {
+            return Err(Error::SpecialFile { path: path.to_path_buf(), file_type: entry.file_type });
+        }
+        if self.secure {
+            check_ownership(path, entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that two paths can be exchanged, before touching either of them.
+///
+/// Returns `Ok(false)` if both paths refer to the same object, in which case exchanging them is
+/// a no-op. Fails with `Error::Nested` if one path is inside the other, as no exchange can
+/// succeed in that case: the descendant would have to be moved into itself. Mount points and
+/// special files fail with `Error::MountPoint` and `Error::SpecialFile`, unless the policy
+/// allows them.
+pub fn check<F: Backend>(backend: &F, policy: &Policy, path1: &path::Path, path2: &path::Path) -> Result<bool> {
+    let entry1 = backend.lookup(path1)?;
+    let entry2 = backend.lookup(path2)?;
+    trace!("resolved {} to {:?} and {} to {:?}", path1.display(), entry1, path2.display(), entry2);
+
+    if entry1.is_same(&entry2) {
+        return Ok(false);
+    }
+    policy.allows(path1, &entry1)?;
+    policy.allows(path2, &entry2)?;
+    if entry1.contains(&entry2) {
+        return Err(Error::Nested { ancestor: path1.to_path_buf(), descendant: path2.to_path_buf() });
+    }
+    if entry2.contains(&entry1) {
+        return Err(Error::Nested { ancestor: path2.to_path_buf(), descendant: path1.to_path_buf() });
+    }
+    Ok(true)
+}
+
+/// Fail if users other than the owners can modify the entry or replace it in its parent directory.
+///
+/// A parent directory that others can write to is only accepted with the sticky bit, if the entry
+/// belongs to the owner of the directory or to the current user: others can't rename it then.
+/// The permissions of symbolic links don't matter, they can't be changed. Entries without known
+/// ownership are refused.
+pub(crate) fn check_ownership(path: &path::Path, entry: &Entry) -> Result<()> {
+    let insecure = |reason| Err(Error::Insecure { path: path.to_path_buf(), reason });
+    let (ownership, parent) = match (entry.ownership, entry.parent_ownership) {
+        (Some(ownership), Some(parent)) => (ownership, parent),
+        _ => return insecure("has an unknown owner"),
+    };
+    if entry.file_type != FileType::Symlink && ownership.writable_by_others() {
+        return insecure("is writable by its group or by others");
+    }
+    if parent.writable_by_others() {
+        let protected = parent.is_sticky() && (ownership.uid == parent.uid || Some(ownership.uid) == platform::effective_uid());
+        if !protected {
+            return insecure("is in a directory that is writable by its group or by others");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::{
+    fs,
+    io,
+    path,
+};
+
+use crate::{
+    error::{Error, Result},
+    non_atomic::TEMP_PREFIX,
+    platform,
+};
+
+/// Atomically replace the file `name` in `dir` with new content, or create it.
+///
+/// `write` is called with an unnamed file created with `O_TMPFILE` in `dir`, so the new content
+/// never shows up under a temporary name while it is written. Afterwards, the file is synced,
+/// linked to a hidden name starting with `.xch-tmp-`, and exchanged with the target. The old
+/// content is removed. If the target does not exist, the file is moved there without replacing
+/// anything that appeared in the meantime. Readers see either the complete old or the complete
+/// new content.
+///
+/// If the target exists, its permissions are copied to the new file. On file systems without
+/// `O_TMPFILE`, the content is written to a hidden, named temporary file instead. If `write` or
+/// any later step fails, the target is left untouched and the temporary file is removed.
+///
+/// Returns the result of `write`. Only available on Linux.
+///
+/// ```no_run
+/// use std::io::Write;
+///
+/// libxch::publish("/etc/myapp", "config.toml", |file| file.write_all(b"answer = 42\n"))?;
+/// # Ok::<(), libxch::Error>(())
+/// ```
+pub fn publish<D, N, F, T>(dir: D, name: N, write: F) -> Result<T>
+    where D: AsRef<path::Path>, N: AsRef<path::Path>, F: FnOnce(&mut fs::File) -> io::Result<T>
+{
+    let dir = dir.as_ref();
+    let target = dir.join(name.as_ref());
+    let hidden = dir.join(format!("{}{}", TEMP_PREFIX, uuid::Uuid::new_v4().to_hyphenated()));
+
+    let (mut file, named) = match platform::open_tmpfile(dir) {
+        Ok(file) => (file, false),
+        Err(ref e) if platform::is_tmpfile_unsupported(e) => {
+            debug!("{} does not support O_TMPFILE, writing to {}", dir.display(), hidden.display());
+            (fs::OpenOptions::new().write(true).create_new(true).open(&hidden)?, true)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let res = prepare(&mut file, &target, write).and_then(|value| {
+        if !named {
+            platform::link_fd(&file, &hidden)?;
+        }
+        Ok(value)
+    });
+    let value = match res {
+        Ok(value) => value,
+        Err(e) => {
+            if named {
+                let _ = fs::remove_file(&hidden);
+            }
+            return Err(e.into());
+        }
+    };
+
+    if let Err(e) = replace(&hidden, &target) {
+        let _ = fs::remove_file(&hidden);
+        return Err(e);
+    }
+    Ok(value)
+}
+
+/// Write the content, take over the permissions of the target and sync everything to disk.
+fn prepare<F, T>(file: &mut fs::File, target: &path::Path, write: F) -> io::Result<T>
+    where F: FnOnce(&mut fs::File) -> io::Result<T>
+{
+    let value = write(file)?;
+    match fs::metadata(target) {
+        Ok(meta) => file.set_permissions(meta.permissions())?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    file.sync_all()?;
+    Ok(value)
+}
+
+/// Move `hidden` to `target`, exchanging it with the old target if there is one.
+fn replace(hidden: &path::Path, target: &path::Path) -> Result<()> {
+    match fs::symlink_metadata(target) {
+        Ok(ref meta) if meta.is_dir() => {
+            return Err(format!("Cannot publish a file over the directory {}", target.display()).into());
+        }
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => match platform::rename_noreplace(hidden, target) {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        },
+        Err(e) => return Err(Error::Fs(e)),
+    }
+
+    platform::xch(hidden, target)?;
+    debug!("published {}, removing old content at {}", target.display(), hidden.display());
+    // The new content is in place, failing to clean up only leaves an orphan behind.
+    if let Err(e) = fs::remove_file(hidden) {
+        debug!("could not remove {}: {}", hidden.display(), e);
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::{
+    fs,
+    io,
+    path,
+};
+
+use crate::{
+    error::{Error, Result},
+    non_atomic::TEMP_PREFIX,
+};
+
+/// Atomically point the symbolic link `link` to `target`.
+///
+/// A new symbolic link is created under a temporary name next to `link` and then renamed over it,
+/// so `link` always points either to its old or to its new target. If `link` does not exist yet,
+/// it is created. If it exists but is not a symbolic link, nothing is changed and an error is
+/// returned.
+pub fn relink<L: AsRef<path::Path>, T: AsRef<path::Path>>(link: L, target: T) -> Result<()> {
+    let link = link.as_ref();
+    match fs::symlink_metadata(link) {
+        Ok(ref meta) if !meta.file_type().is_symlink() => {
+            return Err(format!("{} is not a symbolic link", link.display()).into());
+        }
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let parent = link.parent()
+        .ok_or_else::<Error, _>(|| format!("Could not find parent directory for {}", link.display()).into())?;
+    let temp_name = parent.join(format!("{}{}", TEMP_PREFIX, uuid::Uuid::new_v4().to_hyphenated()));
+    symlink(target.as_ref(), &temp_name)?;
+    fs::rename(&temp_name, link).map_err(|e| {
+        let _ = fs::remove_file(&temp_name);
+        e.into()
+    })
+}
+
+#[cfg(unix)]
+fn symlink(target: &path::Path, path: &path::Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(target, path).map_err(Into::into)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &path::Path, _path: &path::Path) -> Result<()> {
+    Err(Error::NotImplemented)
+}
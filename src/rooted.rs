@@ -0,0 +1,318 @@
+use std::{
+    ffi,
+    fs,
+    io,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path,
+};
+
+#[cfg(feature = "checksum")]
+use crate::checksum::{self, Digest};
+use crate::{
+    backend::{self, Backend, Entry},
+    error::Result,
+    labels::{self, Labels},
+    platform,
+    preflight,
+};
+
+/// A backend that resolves all paths beneath a root directory, like a `chroot` would.
+///
+/// Absolute paths start at the root, `..` never leaves it, and absolute symbolic links are
+/// resolved against it instead of the root of the calling process. This makes it possible to
+/// exchange paths inside a container from the host, through `/proc/<pid>/root`. Paths are
+/// resolved with `openat2(RESOLVE_IN_ROOT | RESOLVE_NO_MAGICLINKS)`, and the entries are renamed
+/// relative to the file descriptors of their parent directories.
+///
+/// Every operation resolves the paths again, so a parent directory that is replaced between the
+/// checks and the exchange is resolved under the same rules. With `no_symlinks`, or with the
+/// backend from `hardened`, a symbolic link in a parent directory fails with `ELOOP`, which
+/// `Error::kind` reports as `ErrorKind::Refused`.
+///
+/// With `secure`, the ownership of both entries and of the parent directories is checked right
+/// before the exchange, on the same directory file descriptors the exchange renames in.
+///
+/// Hooks and errors see the paths as they were given, relative to the root. Needs Linux 5.6 or
+/// later, unless symbolic links are refused. Only available on Linux.
+///
+/// ```no_run
+/// let container = libxch::Rooted::open("/proc/4242/root")?;
+/// libxch::Options::new().xch_with(&container, "/srv/current", "/srv/next")?;
+/// # Ok::<(), libxch::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Rooted {
+    root: fs::File,
+    /// Where relative paths start in `hardened` mode. Absolute paths start at `root` then.
+    cwd: Option<fs::File>,
+    resolve: u64,
+    secure: bool,
+}
+
+impl Rooted {
+    /// Open the directory `root` and resolve all paths beneath it.
+    pub fn open<P: AsRef<path::Path>>(root: P) -> io::Result<Rooted> {
+        let root = root.as_ref();
+        let dir = fs::File::open(root)?;
+        if !dir.metadata()?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a directory", root.display())));
+        }
+        Ok(Rooted::new(dir))
+    }
+
+    /// Resolve all paths beneath the open directory `root`.
+    pub fn new(root: fs::File) -> Rooted {
+        Rooted {
+            root,
+            cwd: None,
+            resolve: platform::RESOLVE_IN_ROOT | platform::RESOLVE_NO_MAGICLINKS,
+            secure: false,
+        }
+    }
+
+    /// Resolve paths from `/` and the current directory, without following any symbolic link in
+    /// their parent directories, and without leaving these directories through `..`.
+    ///
+    /// This protects an exchange in a directory that other users can write to: they can't
+    /// redirect it by replacing a parent directory with a symbolic link. The last component of
+    /// a path may still be a symbolic link, which is exchanged itself. Paths are resolved with
+    /// `openat2(RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS)`, or by opening one component after the
+    /// other with `O_NOFOLLOW` on kernels before 5.6.
+    ///
+    /// ```no_run
+    /// let hardened = libxch::Rooted::hardened()?;
+    /// libxch::Options::new().xch_with(&hardened, "/tmp/shared/a", "/tmp/shared/b")?;
+    /// # Ok::<(), libxch::Error>(())
+    /// ```
+    pub fn hardened() -> io::Result<Rooted> {
+        Ok(Rooted {
+            root: fs::File::open("/")?,
+            cwd: Some(fs::File::open(".")?),
+            resolve: platform::RESOLVE_BENEATH,
+            secure: false,
+        }.no_symlinks(true))
+    }
+
+    /// Refuse to follow symbolic links in the parent directories of the paths.
+    pub fn no_symlinks(mut self, refuse: bool) -> Self {
+        if refuse {
+            self.resolve |= platform::RESOLVE_NO_SYMLINKS;
+        } else {
+            self.resolve &= !platform::RESOLVE_NO_SYMLINKS;
+        }
+        self
+    }
+
+    /// Refuse to exchange entries that users other than their owners can modify, like
+    /// `Options::secure` does before the exchange.
+    ///
+    /// The check is repeated in `exchange`, on the parent directories that were opened for the
+    /// exchange itself, so replacing a parent directory after the checks of `Options` has no
+    /// effect. Renames of the non-atomic fallback are not checked again.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> &fs::File {
+        &self.root
+    }
+
+    /// Block until `path` exists beneath the root, like `wait_for`.
+    ///
+    /// The directory of `path` and its parent are resolved beneath the root again after every
+    /// change, so a staged directory that is moved into place is noticed as well.
+    pub fn wait_for<P: AsRef<path::Path>>(&self, path: P) -> io::Result<()> {
+        use std::io::Read;
+
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or_else(|| path::Path::new("."));
+        let mut inotify = platform::inotify_init()?;
+        let mut events = [0; 4096];
+        debug!("waiting for {} to appear", path.display());
+        loop {
+            if dir.file_name().is_some() {
+                let (outer, _) = self.parent(dir)?;
+                platform::inotify_watch_dir(&inotify, &Rooted::fd_path(&outer, ".".as_ref()))?;
+            }
+            let found = match self.parent(path) {
+                Ok((dir, name)) => {
+                    platform::inotify_watch_dir(&inotify, &Rooted::fd_path(&dir, ".".as_ref()))?;
+                    match self.entry(&dir, name) {
+                        Ok(_) => true,
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e),
+            };
+            if found {
+                return Ok(());
+            }
+            // The events themselves do not matter, only that something changed.
+            let read = inotify.read(&mut events)?;
+            trace!("read {} bytes of inotify events", read);
+        }
+    }
+
+    /// Remove the file at `path` beneath the root, relative to its parent directory.
+    pub fn remove_file<P: AsRef<path::Path>>(&self, path: P) -> io::Result<()> {
+        let (dir, name) = self.parent(path.as_ref())?;
+        platform::unlink_at(&dir, name)
+    }
+
+    /// Open the parent directory of `path` beneath the root, and return it with the last component.
+    fn parent<'a>(&self, path: &'a path::Path) -> io::Result<(fs::File, &'a ffi::OsStr)> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if parent.as_os_str().is_empty() => (path::Path::new("."), name),
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no file name", path.display()))),
+        };
+        // Absolute paths start at the root. RESOLVE_BENEATH refuses them, so they are made relative.
+        let (base, parent) = match (parent.strip_prefix("/"), &self.cwd) {
+            (Ok(relative), _) => (&self.root, relative),
+            (Err(_), Some(cwd)) => (cwd, parent),
+            (Err(_), None) => (&self.root, parent),
+        };
+        let parent = if parent.as_os_str().is_empty() { path::Path::new(".") } else { parent };
+        let res = match platform::open_beneath(base, parent, true, self.resolve) {
+            Err(ref e) if e.raw_os_error() == Some(ENOSYS) && self.can_walk() => walk(base, parent),
+            res => res,
+        };
+        match res {
+            Err(ref e) if e.raw_os_error() == Some(EXDEV) && self.resolve & platform::RESOLVE_BENEATH != 0 => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} leads out of the current directory", path.display())))
+            }
+            res => Ok((res?, name)),
+        }
+    }
+
+    /// Open the entry `name` in the directory `dir` itself, without following a symbolic link.
+    fn entry(&self, dir: &fs::File, name: &ffi::OsStr) -> io::Result<fs::File> {
+        match platform::open_beneath(dir, path::Path::new(name), false, self.resolve) {
+            Err(ref e) if e.raw_os_error() == Some(ENOSYS) && self.can_walk() => platform::open_nofollow(dir, name),
+            res => res,
+        }
+    }
+
+    /// Look up the entry `name` in the open directory `dir`, see `Backend::lookup`.
+    fn entry_in(&self, dir: &fs::File, name: &ffi::OsStr) -> io::Result<Entry> {
+        let file = self.entry(dir, name)?;
+        let meta = file.metadata()?;
+        let dir_meta = dir.metadata()?;
+        let resolved = fs::read_link(path::Path::new("/proc/self/fd").join(dir.as_raw_fd().to_string()))?.join(name);
+        Ok(Entry {
+            path: resolved,
+            id: Some((meta.dev(), meta.ino())),
+            file_type: backend::file_type(&meta),
+            mount_point: meta.is_dir() && meta.dev() != dir_meta.dev(),
+            ownership: backend::ownership(&meta),
+            parent_ownership: backend::ownership(&dir_meta),
+        })
+    }
+
+    /// Check if the resolution can be emulated without `openat2`: only if no symbolic link is
+    /// followed, as `RESOLVE_IN_ROOT` would have to resolve them.
+    fn can_walk(&self) -> bool {
+        self.resolve & platform::RESOLVE_NO_SYMLINKS != 0
+    }
+
+    /// The path of `name` in the open directory `dir`, through `/proc/self/fd`.
+    ///
+    /// Only the directory is resolved through the magic link, `name` is looked up in it.
+    fn fd_path(dir: &fs::File, name: &ffi::OsStr) -> path::PathBuf {
+        path::Path::new("/proc/self/fd").join(dir.as_raw_fd().to_string()).join(name)
+    }
+}
+
+impl Backend for Rooted {
+    fn exchange(&self, path1: &path::Path, path2: &path::Path) -> Result<()> {
+        let (dir1, name1) = self.parent(path1)?;
+        let (dir2, name2) = self.parent(path2)?;
+        if self.secure {
+            preflight::check_ownership(path1, &self.entry_in(&dir1, name1)?)?;
+            preflight::check_ownership(path2, &self.entry_in(&dir2, name2)?)?;
+        }
+        platform::exchange_at(&dir1, name1, &dir2, name2)
+    }
+
+    fn supports_exchange(&self) -> bool {
+        true
+    }
+
+    fn lookup(&self, path: &path::Path) -> io::Result<Entry> {
+        let (dir, name) = self.parent(path)?;
+        self.entry_in(&dir, name)
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        platform::rename_at(&from_dir, from_name, &to_dir, to_name, false)
+    }
+
+    fn rename_noreplace(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        platform::rename_at(&from_dir, from_name, &to_dir, to_name, true)
+    }
+
+    fn write_sidecar(&self, path: &path::Path, content: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        let (dir, name) = self.parent(path)?;
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(Rooted::fd_path(&dir, name))?;
+        file.write_all(content)
+    }
+
+    fn remove_sidecar(&self, path: &path::Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        fs::remove_file(Rooted::fd_path(&dir, name))
+    }
+
+    #[cfg(feature = "checksum")]
+    fn digest(&self, path: &path::Path) -> io::Result<Digest> {
+        let (dir, name) = self.parent(path)?;
+        checksum::digest(Rooted::fd_path(&dir, name))
+    }
+
+    fn labels(&self, path: &path::Path) -> io::Result<Labels> {
+        let (dir, name) = self.parent(path)?;
+        labels::labels(Rooted::fd_path(&dir, name))
+    }
+
+    fn set_label(&self, path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+        let (dir, entry) = self.parent(path)?;
+        labels::set_label(Rooted::fd_path(&dir, entry), name, value)
+    }
+}
+
+/// Open the directory `path` beneath `base`, one component after the other, refusing symbolic
+/// links and `..`, like `openat2(RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS)` does.
+fn walk(base: &fs::File, path: &path::Path) -> io::Result<fs::File> {
+    trace!("openat2 is not available, opening {} one component after the other", path.display());
+    let mut dir = base.try_clone()?;
+    for component in path.components() {
+        match component {
+            path::Component::Normal(name) => {
+                let next = platform::open_nofollow(&dir, name)?;
+                let file_type = next.metadata()?.file_type();
+                if file_type.is_symlink() {
+                    return Err(io::Error::from_raw_os_error(ELOOP));
+                } else if !file_type.is_dir() {
+                    return Err(io::Error::from_raw_os_error(ENOTDIR));
+                }
+                dir = next;
+            }
+            path::Component::CurDir => (),
+            _ => return Err(io::Error::from_raw_os_error(EXDEV)),
+        }
+    }
+    Ok(dir)
+}
+
+const ENOTDIR: i32 = 20;
+const EXDEV: i32 = 18;
+const ENOSYS: i32 = 38;
+const ELOOP: i32 = 40;
@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    io,
+    path,
+};
+
+use crate::{
+    cloning::{self, CloneMethod},
+    error::Result,
+    options::{Options, Strategy},
+};
+
+/// Prefix of the staging directories created by `stage`.
+pub const STAGE_PREFIX: &str = ".xch-stage-";
+
+/// How the files of the live tree end up in the staging copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Copy the content of every file, sharing the data blocks with the live file on file systems
+    /// that support it. See `clone_file`.
+    Copy,
+    /// Hard link every file to the live file.
+    ///
+    /// This is fast and needs no space, but a staged file shares its content with the live file.
+    /// Modifying it in place also modifies the live tree: replace staged files instead, for
+    /// example by writing a new file and renaming it over the old one.
+    Hardlink,
+}
+
+/// A staging copy of a directory, that can be exchanged into place.
+///
+/// Create one with `stage`, modify the tree at `path()`, then `commit()` it. After the commit,
+/// `path()` holds the old tree, until it is removed with `discard()`. A stage that is dropped
+/// without calling `discard()` stays on disk.
+///
+/// ```no_run
+/// let mut stage = libxch::stage("/srv/www")?;
+/// std::fs::write(stage.path().join("index.html"), b"<h1>New version</h1>")?;
+/// stage.commit()?;
+/// // stage.path() now holds the old version.
+/// stage.discard()?;
+/// # Ok::<(), libxch::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Stage {
+    live: path::PathBuf,
+    staged: path::PathBuf,
+    committed: bool,
+    copies: Vec<(path::PathBuf, CloneMethod)>,
+}
+
+/// Create a staging copy of the directory `dir`, next to it.
+///
+/// This is `Stage::create(dir, CopyMode::Copy)`.
+pub fn stage<P: AsRef<path::Path>>(dir: P) -> Result<Stage> {
+    Stage::create(dir, CopyMode::Copy)
+}
+
+impl Stage {
+    /// Create a staging copy of the directory `dir`, next to it.
+    ///
+    /// The copy is a new directory in the parent of `dir`, with a name starting with
+    /// `.xch-stage-`. Files, directories and symbolic links are copied, other types of files
+    /// are not supported. On error, the partial copy is removed.
+    ///
+    /// Fails if `dir` is a symbolic link, even to a directory: the copy would only be another
+    /// link, so changes to the stage would change the live tree right away.
+    pub fn create<P: AsRef<path::Path>>(dir: P, mode: CopyMode) -> Result<Stage> {
+        let live = dir.as_ref().to_path_buf();
+        // Not following a symbolic link: its copy would be another link to the live tree.
+        let file_type = fs::symlink_metadata(&live)?.file_type();
+        if file_type.is_symlink() {
+            return Err(format!("{} is a symbolic link, stage the directory it points to instead", live.display()).into());
+        } else if !file_type.is_dir() {
+            return Err(format!("{} is not a directory", live.display()).into());
+        }
+        let parent = match live.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => path::Path::new("."),
+            Some(parent) => parent,
+            None => return Err(format!("Could not find parent directory for {}", live.display()).into()),
+        };
+        let staged = parent.join(format!("{}{}", STAGE_PREFIX, uuid::Uuid::new_v4().to_hyphenated()));
+
+        debug!("staging {} at {}", live.display(), staged.display());
+        let mut copies = Vec::new();
+        if let Err(e) = copy_tree(&live, &staged, path::Path::new(""), mode, &mut copies) {
+            let _ = fs::remove_dir_all(&staged);
+            return Err(e.into());
+        }
+        Ok(Stage {
+            live,
+            staged,
+            committed: false,
+            copies,
+        })
+    }
+
+    /// The staging copy, before the commit. The old tree, after the commit.
+    pub fn path(&self) -> &path::Path {
+        &self.staged
+    }
+
+    /// The live directory, as passed to `stage`.
+    pub fn live(&self) -> &path::Path {
+        &self.live
+    }
+
+    /// The files that were copied into the stage, relative to its root, with the method used for
+    /// each. Empty for `CopyMode::Hardlink`.
+    pub fn copies(&self) -> &[(path::PathBuf, CloneMethod)] {
+        &self.copies
+    }
+
+    /// Check if the stage was committed.
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+
+    /// Atomically exchange the staging copy with the live directory.
+    pub fn commit(&mut self) -> Result<()> {
+        self.commit_with(&Options::new()).map(|_| ())
+    }
+
+    /// Exchange the staging copy with the live directory, using the given options.
+    ///
+    /// Fails if the stage was already committed.
+    pub fn commit_with(&mut self, options: &Options) -> Result<Strategy> {
+        if self.committed {
+            return Err(format!("{} was already committed", self.staged.display()).into());
+        }
+        let strategy = options.xch(&self.live, &self.staged)?;
+        self.committed = true;
+        Ok(strategy)
+    }
+
+    /// Remove the staging directory, with the old tree if the stage was committed.
+    pub fn discard(self) -> Result<()> {
+        fs::remove_dir_all(&self.staged).map_err(Into::into)
+    }
+}
+
+/// Copy the tree at `from` to the new path `to`, without following symbolic links.
+///
+/// `relative` is the path of `from` relative to the root of the copy, used to record `copies`.
+fn copy_tree(from: &path::Path, to: &path::Path, relative: &path::Path, mode: CopyMode, copies: &mut Vec<(path::PathBuf, CloneMethod)>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        copy_symlink(from, to)
+    } else if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            copy_tree(&entry.path(), &to.join(&name), &relative.join(&name), mode, copies)?;
+        }
+        fs::set_permissions(to, meta.permissions())
+    } else if file_type.is_file() {
+        match mode {
+            CopyMode::Copy => {
+                let method = cloning::clone_file(from, to)?;
+                copies.push((relative.to_path_buf(), method));
+                Ok(())
+            }
+            CopyMode::Hardlink => fs::hard_link(from, to),
+        }
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot copy special file {}", from.display())))
+    }
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(windows)]
+fn copy_symlink(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    let target = fs::read_link(from)?;
+    if fs::metadata(from).map(|m| m.is_dir()).unwrap_or(false) {
+        ::std::os::windows::fs::symlink_dir(target, to)
+    } else {
+        ::std::os::windows::fs::symlink_file(target, to)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn copy_symlink(from: &path::Path, _to: &path::Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, format!("Cannot copy symbolic link {}", from.display())))
+}
@@ -0,0 +1,497 @@
+//! An in-memory file system for testing code that exchanges paths.
+//!
+//! `FakeFs` implements `Backend` without touching the real file system. Failures can be injected
+//! into the atomic exchange, into any rename of the non-atomic fallback, into any rename that is
+//! executed while rolling back and into setting security labels, so error handling can be tested
+//! deterministically.
+//!
+//! ```
+//! use libxch::testing::FakeFs;
+//!
+//! let fs = FakeFs::without_exchange();
+//! fs.create_file("a", b"content a").unwrap();
+//! fs.create_file("b", b"content b").unwrap();
+//! // Fail the second rename of the fallback, and the first rename of the rollback.
+//! fs.fail_rename(2, 18);
+//! fs.fail_revert(1, 13);
+//!
+//! assert!(libxch::xch_non_atomic_with(&fs, "a", "b").is_err());
+//! assert_eq!(fs.read_file("b").unwrap(), b"content b");
+//! assert!(!fs.exists("a"));
+//! ```
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path,
+    sync::Mutex,
+};
+
+#[cfg(feature = "checksum")]
+use crate::checksum::{self, Digest};
+use crate::{
+    backend::{Backend, Entry, FileType},
+    error::{Error, Result},
+    labels::Labels,
+};
+
+/// A single entry in the fake file system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    File(Vec<u8>),
+    Dir,
+    Symlink(path::PathBuf),
+    /// A FIFO, socket or device node.
+    Special(FileType),
+}
+
+/// An operation that was requested from a `FakeFs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Exchange(path::PathBuf, path::PathBuf),
+    Rename(path::PathBuf, path::PathBuf),
+    Revert(path::PathBuf, path::PathBuf),
+}
+
+/// An in-memory file system implementing `Backend`.
+///
+/// Paths are compared lexically, they are never canonicalized. An empty parent, i.e. a relative
+/// path with only one component, or the root directory always exist.
+#[derive(Debug)]
+pub struct FakeFs {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    nodes: BTreeMap<path::PathBuf, Node>,
+    /// Security labels of the nodes that have any, moved along with them.
+    labels: BTreeMap<path::PathBuf, Labels>,
+    mounts: BTreeSet<path::PathBuf>,
+    atomic: bool,
+    exchange_fault: Option<i32>,
+    rename_faults: BTreeMap<usize, i32>,
+    revert_faults: BTreeMap<usize, i32>,
+    label_faults: BTreeMap<path::PathBuf, i32>,
+    corruption: Option<(path::PathBuf, Vec<u8>)>,
+    renames: usize,
+    reverts: usize,
+    log: Vec<Operation>,
+}
+
+impl FakeFs {
+    /// Create an empty file system that supports atomic exchange.
+    pub fn new() -> Self {
+        FakeFs::with_atomic(true)
+    }
+
+    /// Create an empty file system that does not support atomic exchange.
+    ///
+    /// `Backend::exchange` always returns `Error::NotImplemented`, so only the non-atomic
+    /// fallback can be used.
+    pub fn without_exchange() -> Self {
+        FakeFs::with_atomic(false)
+    }
+
+    fn with_atomic(atomic: bool) -> Self {
+        FakeFs {
+            state: Mutex::new(State {
+                nodes: BTreeMap::new(),
+                labels: BTreeMap::new(),
+                mounts: BTreeSet::new(),
+                atomic,
+                exchange_fault: None,
+                rename_faults: BTreeMap::new(),
+                revert_faults: BTreeMap::new(),
+                label_faults: BTreeMap::new(),
+                corruption: None,
+                renames: 0,
+                reverts: 0,
+                log: Vec::new(),
+            }),
+        }
+    }
+
+    /// Create an empty directory. The parent directory has to exist.
+    pub fn create_dir<P: AsRef<path::Path>>(&self, path: P) -> io::Result<()> {
+        self.insert(path.as_ref(), Node::Dir)
+    }
+
+    /// Create a file with the given content. The parent directory has to exist.
+    pub fn create_file<P: AsRef<path::Path>>(&self, path: P, content: &[u8]) -> io::Result<()> {
+        self.insert(path.as_ref(), Node::File(content.to_vec()))
+    }
+
+    /// Create a symbolic link pointing to `target`. The parent directory has to exist.
+    pub fn create_symlink<P: AsRef<path::Path>, T: AsRef<path::Path>>(&self, path: P, target: T) -> io::Result<()> {
+        self.insert(path.as_ref(), Node::Symlink(target.as_ref().to_path_buf()))
+    }
+
+    /// Create a FIFO, socket or device node. The parent directory has to exist.
+    pub fn create_special<P: AsRef<path::Path>>(&self, path: P, file_type: FileType) -> io::Result<()> {
+        self.insert(path.as_ref(), Node::Special(file_type))
+    }
+
+    /// Mark the existing directory at `path` as the root of a mounted file system.
+    ///
+    /// Like on Linux, renaming or exchanging a mount point fails with `EBUSY`.
+    pub fn mount<P: AsRef<path::Path>>(&self, path: P) -> io::Result<()> {
+        let mut state = self.lock();
+        match state.nodes.get(path.as_ref()) {
+            Some(Node::Dir) => (),
+            Some(_) => return Err(not_a_directory()),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        }
+        state.mounts.insert(path.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    /// Get the entry at the given path, if it exists.
+    pub fn get<P: AsRef<path::Path>>(&self, path: P) -> Option<Node> {
+        self.lock().nodes.get(path.as_ref()).cloned()
+    }
+
+    /// Get the content of the file at the given path, if it exists and is a file.
+    pub fn read_file<P: AsRef<path::Path>>(&self, path: P) -> Option<Vec<u8>> {
+        match self.get(path) {
+            Some(Node::File(content)) => Some(content),
+            _ => None,
+        }
+    }
+
+    /// Check if the given path exists.
+    pub fn exists<P: AsRef<path::Path>>(&self, path: P) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// All entries of the file system, sorted by path.
+    pub fn entries(&self) -> Vec<(path::PathBuf, Node)> {
+        self.lock().nodes.iter().map(|(p, n)| (p.clone(), n.clone())).collect()
+    }
+
+    /// All operations requested so far, including the ones that failed.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.lock().log.clone()
+    }
+
+    /// Make every following atomic exchange fail with the given OS error code.
+    ///
+    /// On Linux, the error is an `Error::PlatformError`, like the one of `renameat2`, so `EINVAL`
+    /// and `ENOSYS` mean that atomic exchange is not supported.
+    pub fn fail_exchange(&self, errno: i32) {
+        self.lock().exchange_fault = Some(errno);
+    }
+
+    /// Make the `n`-th rename fail with the given OS error code.
+    ///
+    /// Calls to `Backend::rename` and `Backend::rename_noreplace` are counted together, from 1
+    /// over the lifetime of this file system. Renames executed during a rollback are counted
+    /// separately, see `fail_revert`.
+    pub fn fail_rename(&self, n: usize, errno: i32) {
+        self.lock().rename_faults.insert(n, errno);
+    }
+
+    /// Make the `m`-th call to `Backend::revert` fail with the given OS error code.
+    ///
+    /// Calls are counted from 1 over the lifetime of this file system.
+    pub fn fail_revert(&self, m: usize, errno: i32) {
+        self.lock().revert_faults.insert(m, errno);
+    }
+
+    /// Make every following `Backend::set_label` on `path` fail with the given OS error code.
+    pub fn fail_set_label<P: AsRef<path::Path>>(&self, path: P, errno: i32) {
+        self.lock().label_faults.insert(path.as_ref().to_path_buf(), errno);
+    }
+
+    /// Replace the content of the file at `path` right after the first successful exchange or
+    /// rename that leaves a file at `path`, like a concurrent writer would.
+    pub fn corrupt<P: AsRef<path::Path>>(&self, path: P, content: &[u8]) {
+        self.lock().corruption = Some((path.as_ref().to_path_buf(), content.to_vec()));
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn insert(&self, path: &path::Path, node: Node) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.nodes.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        state.check_parent(path)?;
+        state.nodes.insert(path.to_path_buf(), node);
+        Ok(())
+    }
+}
+
+impl Default for FakeFs {
+    fn default() -> Self {
+        FakeFs::new()
+    }
+}
+
+impl Backend for FakeFs {
+    fn exchange(&self, path1: &path::Path, path2: &path::Path) -> Result<()> {
+        let mut state = self.lock();
+        state.log.push(Operation::Exchange(path1.to_path_buf(), path2.to_path_buf()));
+        if !state.atomic {
+            return Err(Error::NotImplemented);
+        }
+        if let Some(errno) = state.exchange_fault {
+            return Err(exchange_error(errno));
+        }
+        state.exchange(path1, path2)?;
+        state.corrupt();
+        Ok(())
+    }
+
+    fn supports_exchange(&self) -> bool {
+        self.lock().atomic
+    }
+
+    fn lookup(&self, path: &path::Path) -> io::Result<Entry> {
+        let state = self.lock();
+        let file_type = match state.nodes.get(path) {
+            Some(Node::File(_)) => FileType::File,
+            Some(Node::Dir) => FileType::Dir,
+            Some(Node::Symlink(_)) => FileType::Symlink,
+            Some(Node::Special(file_type)) => *file_type,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Entry {
+            path: path.to_path_buf(),
+            id: None,
+            file_type,
+            mount_point: state.mounts.contains(path),
+            ownership: None,
+            parent_ownership: None,
+        })
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.log.push(Operation::Rename(from.to_path_buf(), to.to_path_buf()));
+        state.renames += 1;
+        if let Some(&errno) = state.rename_faults.get(&state.renames) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        state.rename(from, to)?;
+        state.corrupt();
+        Ok(())
+    }
+
+    fn rename_noreplace(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.log.push(Operation::Rename(from.to_path_buf(), to.to_path_buf()));
+        state.renames += 1;
+        if let Some(&errno) = state.rename_faults.get(&state.renames) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        if state.nodes.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        state.rename(from, to)?;
+        state.corrupt();
+        Ok(())
+    }
+
+    fn revert(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.log.push(Operation::Revert(from.to_path_buf(), to.to_path_buf()));
+        state.reverts += 1;
+        if let Some(&errno) = state.revert_faults.get(&state.reverts) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        state.rename(to, from)
+    }
+
+    #[cfg(feature = "checksum")]
+    fn digest(&self, path: &path::Path) -> io::Result<Digest> {
+        self.lock().digest(path)
+    }
+
+    fn labels(&self, path: &path::Path) -> io::Result<Labels> {
+        let state = self.lock();
+        if !state.nodes.contains_key(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(state.labels.get(path).cloned().unwrap_or_default())
+    }
+
+    fn set_label(&self, path: &path::Path, name: &str, value: Option<&[u8]>) -> io::Result<()> {
+        let mut state = self.lock();
+        if !state.nodes.contains_key(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if let Some(&errno) = state.label_faults.get(path) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        let mut labels = state.labels.remove(path).unwrap_or_default();
+        match value {
+            Some(value) => labels.set(name, value.to_vec()),
+            None => labels.remove(name),
+        }
+        if !labels.is_empty() {
+            state.labels.insert(path.to_path_buf(), labels);
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Ensure the parent of `path` exists and is a directory.
+    fn check_parent(&self, path: &path::Path) -> io::Result<()> {
+        match path.parent() {
+            None => Ok(()),
+            Some(p) if p.as_os_str().is_empty() || p.parent().is_none() => Ok(()),
+            Some(p) => match self.nodes.get(p) {
+                Some(Node::Dir) => Ok(()),
+                Some(_) => Err(not_a_directory()),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    /// Remove the subtree rooted at `path` and return it with paths relative to `path`, along with
+    /// the labels of its nodes.
+    fn take_subtree(&mut self, path: &path::Path) -> Vec<(path::PathBuf, Node, Option<Labels>)> {
+        let keys: Vec<_> = self.nodes.keys()
+            .filter(|k| k.starts_with(path))
+            .cloned()
+            .collect();
+        keys.into_iter()
+            .map(|k| {
+                let node = self.nodes.remove(&k).expect("key was just listed");
+                let labels = self.labels.remove(&k);
+                let rel = k.strip_prefix(path).expect("key starts with path").to_path_buf();
+                (rel, node, labels)
+            })
+            .collect()
+    }
+
+    fn put_subtree(&mut self, path: &path::Path, subtree: Vec<(path::PathBuf, Node, Option<Labels>)>) {
+        for (rel, node, labels) in subtree {
+            let key = if rel.as_os_str().is_empty() { path.to_path_buf() } else { path.join(rel) };
+            if let Some(labels) = labels {
+                self.labels.insert(key.clone(), labels);
+            }
+            self.nodes.insert(key, node);
+        }
+    }
+
+    fn is_mounted(&self, path: &path::Path) -> bool {
+        self.mounts.contains(path)
+    }
+
+    fn is_empty_dir(&self, path: &path::Path) -> bool {
+        !self.nodes.keys().any(|k| k != path && k.starts_with(path))
+    }
+
+    /// Rename following the rules of `rename(2)`.
+    fn rename(&mut self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let from_node = self.nodes.get(from).cloned().ok_or(io::ErrorKind::NotFound)?;
+        self.check_parent(to)?;
+        if self.is_mounted(from) || self.is_mounted(to) {
+            return Err(busy());
+        }
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(invalid_argument());
+        }
+        match (&from_node, self.nodes.get(to)) {
+            (_, None) => (),
+            (Node::Dir, Some(Node::Dir)) if self.is_empty_dir(to) => (),
+            (Node::Dir, Some(Node::Dir)) => return Err(directory_not_empty()),
+            (Node::Dir, Some(_)) => return Err(not_a_directory()),
+            (_, Some(Node::Dir)) => return Err(is_a_directory()),
+            (_, Some(_)) => (),
+        }
+        self.nodes.remove(to);
+        self.labels.remove(to);
+        let subtree = self.take_subtree(from);
+        self.put_subtree(to, subtree);
+        Ok(())
+    }
+
+    /// Apply the pending corruption, if its file exists.
+    fn corrupt(&mut self) {
+        if let Some((path, content)) = self.corruption.take() {
+            match self.nodes.get_mut(&path) {
+                Some(Node::File(old)) => *old = content,
+                _ => self.corruption = Some((path, content)),
+            }
+        }
+    }
+
+    #[cfg(feature = "checksum")]
+    fn digest(&self, path: &path::Path) -> io::Result<Digest> {
+        match self.nodes.get(path) {
+            Some(Node::File(content)) => Digest::file(&content[..]),
+            Some(Node::Symlink(target)) => Ok(Digest::symlink(target)),
+            Some(Node::Dir) => {
+                let mut entries = Vec::new();
+                for child in self.nodes.keys().filter(|k| k.parent() == Some(path)) {
+                    let name = child.file_name().expect("child has a file name");
+                    entries.push((checksum::os_bytes(name), self.digest(child)?));
+                }
+                Ok(Digest::dir(entries))
+            }
+            Some(Node::Special(_)) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot compute the digest of special file {}", path.display()))),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Exchange following the rules of `renameat2(2)` with `RENAME_EXCHANGE`.
+    fn exchange(&mut self, path1: &path::Path, path2: &path::Path) -> io::Result<()> {
+        if !self.nodes.contains_key(path1) || !self.nodes.contains_key(path2) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if path1 == path2 {
+            return Ok(());
+        }
+        if self.is_mounted(path1) || self.is_mounted(path2) {
+            return Err(busy());
+        }
+        if path1.starts_with(path2) || path2.starts_with(path1) {
+            return Err(invalid_argument());
+        }
+        let subtree1 = self.take_subtree(path1);
+        let subtree2 = self.take_subtree(path2);
+        self.put_subtree(path2, subtree1);
+        self.put_subtree(path1, subtree2);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn exchange_error(errno: i32) -> Error {
+    crate::platform::PlatformError::from_raw_os_error(errno).into()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange_error(errno: i32) -> Error {
+    io::Error::from_raw_os_error(errno).into()
+}
+
+#[cfg(target_os = "linux")]
+fn busy() -> io::Error { io::Error::from_raw_os_error(16) }
+#[cfg(target_os = "linux")]
+fn not_a_directory() -> io::Error { io::Error::from_raw_os_error(20) }
+#[cfg(target_os = "linux")]
+fn is_a_directory() -> io::Error { io::Error::from_raw_os_error(21) }
+#[cfg(target_os = "linux")]
+fn invalid_argument() -> io::Error { io::Error::from_raw_os_error(22) }
+#[cfg(target_os = "linux")]
+fn directory_not_empty() -> io::Error { io::Error::from_raw_os_error(39) }
+
+#[cfg(not(target_os = "linux"))]
+fn busy() -> io::Error { io::Error::new(io::ErrorKind::Other, "Device or resource busy") }
+#[cfg(not(target_os = "linux"))]
+fn not_a_directory() -> io::Error { io::Error::new(io::ErrorKind::Other, "Not a directory") }
+#[cfg(not(target_os = "linux"))]
+fn is_a_directory() -> io::Error { io::Error::new(io::ErrorKind::Other, "Is a directory") }
+#[cfg(not(target_os = "linux"))]
+fn invalid_argument() -> io::Error { io::ErrorKind::InvalidInput.into() }
+#[cfg(not(target_os = "linux"))]
+fn directory_not_empty() -> io::Error { io::Error::new(io::ErrorKind::Other, "Directory not empty") }
@@ -0,0 +1,72 @@
+use std::{
+    fs,
+    io,
+    path,
+};
+
+/// Block until `path` exists.
+///
+/// On Linux, this waits for inotify events on the parent directory of `path` and on its parent,
+/// so it notices both a new entry in an existing directory and a directory that is created or
+/// moved into place with the entry already in it. Elsewhere, the path is checked periodically.
+///
+/// Returns immediately if `path` already exists. Fails if the parent of the parent directory
+/// does not exist.
+///
+/// ```no_run
+/// libxch::wait_for("/srv/next/.ready")?;
+/// libxch::xch("/srv/next", "/srv/current")?;
+/// # Ok::<(), libxch::Error>(())
+/// ```
+pub fn wait_for<P: AsRef<path::Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => path::Path::new("."),
+        Some(dir) => dir,
+        None => return fs::symlink_metadata(path).map(|_| ()),
+    };
+    debug!("waiting for {} to appear", path.display());
+    wait(path, dir)
+}
+
+fn exists(path: &path::Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wait(path: &path::Path, dir: &path::Path) -> io::Result<()> {
+    use std::io::Read;
+
+    use crate::platform;
+
+    let outer = dir.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| path::Path::new("."));
+    let mut inotify = platform::inotify_init()?;
+    let mut events = [0; 4096];
+    loop {
+        // Watches are added again after every event: `dir` may have been replaced by a new
+        // directory. Checking only after adding them makes sure no event is missed.
+        platform::inotify_watch_dir(&inotify, outer)?;
+        match platform::inotify_watch_dir(&inotify, dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        if exists(path)? {
+            return Ok(());
+        }
+        // The events themselves do not matter, only that something changed.
+        let read = inotify.read(&mut events)?;
+        trace!("read {} bytes of inotify events", read);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait(path: &path::Path, _dir: &path::Path) -> io::Result<()> {
+    while !exists(path)? {
+        ::std::thread::sleep(::std::time::Duration::from_millis(200));
+    }
+    Ok(())
+}
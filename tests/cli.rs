@@ -0,0 +1,426 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+mod util;
+
+fn xch() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_xch"))
+}
+
+#[test]
+fn test_cli_swap() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    let status = xch().arg("-n").arg(&file1).arg(&file2).status().expect("Could not run xch");
+    assert!(status.success());
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    let status = xch().arg("swap").arg("--").arg(&file1).arg(&file2).status().expect("Could not run xch");
+    assert!(status.success());
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_cli_rotate() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let files: Vec<_> = (1..=3).map(|i| dir.path().join(format!("file{}", i))).collect();
+    for (i, file) in files.iter().enumerate() {
+        util::create_file_with_content(file, format!("content{}", i + 1).as_bytes()).expect("Could not create file in tempdir");
+    }
+
+    let status = xch().arg("rotate").arg("-nV").args(&files).status().expect("Could not run xch");
+    assert!(status.success());
+    assert!(util::ensure_file_content(&files[0], b"content1").expect("Could not read file"));
+
+    let status = xch().arg("rotate").args(&files).status().expect("Could not run xch");
+    assert!(status.success());
+    assert!(util::ensure_file_content(&files[0], b"content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(&files[1], b"content3").expect("Could not read file"));
+    assert!(util::ensure_file_content(&files[2], b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_cli_usage_errors() {
+    for args in &[&["only-one"][..], &["--unknown", "a", "b"], &["rotate", "a"], &["relink", "-n", "a", "b"]] {
+        let output = xch().args(*args).output().expect("Could not run xch");
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("USAGE"), "{:?}", args);
+    }
+
+    // A path named like a command is only swapped after `swap` or `--`.
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    util::create_file_with_content(dir.path().join("check"), b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(dir.path().join("other"), b"content2").expect("Could not create file in tempdir");
+    let output = xch().current_dir(dir.path()).args(["check", "other"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'xch -- check other'"));
+    for args in &[&["swap", "check", "other"][..], &["--", "check", "other"]] {
+        let output = xch().current_dir(dir.path()).args(*args).output().expect("Could not run xch");
+        assert!(output.status.success(), "{:?}", args);
+    }
+    assert!(util::ensure_file_content(dir.path().join("check"), b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_cli_generated_docs() {
+    for shell in &["bash", "zsh", "fish"] {
+        let output = xch().args(["completions", shell]).output().expect("Could not run xch");
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("rotate"));
+    }
+    let output = xch().arg("man").output().expect("Could not run xch");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with(".TH XCH 1"));
+}
+
+#[test]
+fn test_cli_json_output() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let missing = dir.path().join("missing");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+
+    let output = xch().args(["check", "-o", "json"]).arg(&file1).arg(&file1).output().expect("Could not run xch");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("{\"operation\":\"check\",\"paths\":["), "{}", stdout);
+    assert!(stdout.contains("\"strategy\":\"unchanged\""), "{}", stdout);
+    assert!(stdout.contains("\"success\":true,\"error\":null,\"rollback\":\"not_needed\"}"), "{}", stdout);
+    assert_eq!(stdout.lines().count(), 1);
+
+    let output = xch().arg("--output=json").arg(&file1).arg(&missing).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"strategy\":null"), "{}", stdout);
+    assert!(stdout.contains("\"success\":false,\"error\":{\"kind\":\"not_found\",\"errno\":2,"), "{}", stdout);
+
+    // A refused exchange is reported as well, without anything on stderr.
+    let output = xch().args(["--output", "json"]).arg(dir.path()).arg(&file1).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"success\":false,\"error\":{\"kind\":\"refused\",\"errno\":null,"), "{}", stdout);
+    assert!(stdout.ends_with("\"rollback\":\"not_needed\"}\n"), "{}", stdout);
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let root = dir.path().to_str().expect("UTF-8 path");
+    let file = file1.to_str().expect("UTF-8 path");
+    let output = xch_with_stdin(&["--stdin", "-k", "-o", "json"], format!("{}\t{}\n{}\t{}\n", root, file, file, file).as_bytes());
+    assert_eq!(output.status.code(), Some(8));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 2, "{}", stdout);
+    assert!(stdout.lines().next().unwrap_or("").contains("\"kind\":\"refused\""), "{}", stdout);
+
+    let output = xch_with_stdin(&["-i", "-o", "json", root, file], b"n\n");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\"message\":\"declined at the prompt\""));
+
+    let output = xch().args(["-o", "xml"]).arg(&file1).arg(&missing).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_exit_codes() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let sub = dir.path().join("sub");
+    let file = sub.join("file");
+    fs::create_dir(&sub).expect("Could not create directory in tempdir");
+    util::create_file_with_content(&file, b"content").expect("Could not create file in tempdir");
+
+    let output = xch().arg(&file).arg(dir.path().join("missing")).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(3));
+    let output = xch().arg("--force").arg(&sub).arg(&file).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(1));
+    let output = xch().args(["-o", "json", "rotate"]).arg(&file).arg(&file).arg(dir.path().join("missing")).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\"rollback\":\"not_needed\""));
+    if cfg!(unix) {
+        let other = dir.path().join("other");
+        util::create_file_with_content(&other, b"other").expect("Could not create file in tempdir");
+        let output = xch().args(["--exec-before", "false"]).arg(&file).arg(&other).output().expect("Could not run xch");
+        assert_eq!(output.status.code(), Some(9));
+        let output = xch().args(["--verify", "false"]).arg(&file).arg(&other).output().expect("Could not run xch");
+        assert_eq!(output.status.code(), Some(10));
+    }
+
+    // A file on another file system can't be exchanged, unless the fallback copies it.
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let other = tempdir::TempDir::new_in("/dev/shm", "test");
+        if let Ok(other) = other.as_ref().map(|o| o.path().join("file")) {
+            util::create_file_with_content(&other, b"other").expect("Could not create file in /dev/shm");
+            if fs::metadata(&other).map(|m| m.dev()).ok() != fs::metadata(&file).map(|m| m.dev()).ok() {
+                let output = xch().arg(&file).arg(&other).output().expect("Could not run xch");
+                assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+            }
+        }
+    }
+
+    let output = xch().arg("man").output().expect("Could not run xch");
+    let man = String::from_utf8_lossy(&output.stdout);
+    for code in 0..=10 {
+        assert!(man.contains(&format!(".B {}\n", code)), "exit code {} is not documented", code);
+    }
+}
+
+#[test]
+fn test_cli_verbose() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    let output = xch().arg(&file1).arg(&file2).output().expect("Could not run xch");
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = xch().arg("-v").arg(&file1).arg(&file2).output().expect("Could not run xch");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("debug: exchanging "), "{}", stderr);
+    assert!(!stderr.contains("trace:"), "{}", stderr);
+
+    let output = xch().arg("-vv").arg(&file1).arg(&file2).output().expect("Could not run xch");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("trace: "), "{}", stderr);
+    if cfg!(target_os = "linux") {
+        assert!(stderr.contains("RENAME_EXCHANGE) = 0"), "{}", stderr);
+    }
+}
+
+/// Run xch with the given arguments, feeding `input` to stdin.
+fn xch_with_stdin(args: &[&str], input: &[u8]) -> std::process::Output {
+    let mut child = xch().args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not run xch");
+    child.stdin.take().expect("stdin is piped").write_all(input).expect("Could not write to stdin");
+    child.wait_with_output().expect("Could not run xch")
+}
+
+#[test]
+fn test_cli_stdin() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let files: Vec<_> = (1..=4).map(|i| dir.path().join(format!("file {}", i))).collect();
+    for (i, file) in files.iter().enumerate() {
+        util::create_file_with_content(file, format!("content{}", i + 1).as_bytes()).expect("Could not create file in tempdir");
+    }
+    let missing = dir.path().join("missing");
+    let path = |p: &std::path::Path| p.to_str().expect("temporary paths are UTF-8").to_string();
+
+    let input = format!("{}\t{}\n\n{}\t{}\n", path(&files[0]), path(&files[1]), path(&files[2]), path(&files[3]));
+    let output = xch_with_stdin(&["--stdin"], input.as_bytes());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(util::ensure_file_content(&files[0], b"content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(&files[2], b"content4").expect("Could not read file"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("swapped: 2, failed: 0"));
+
+    // Without --keep-going, the first error stops processing.
+    let input = format!("{}\0{}\0{}\0{}\0", path(&files[0]), path(&missing), path(&files[0]), path(&files[1]));
+    let output = xch_with_stdin(&["--stdin", "-0"], input.as_bytes());
+    assert_eq!(output.status.code(), Some(3));
+    assert!(util::ensure_file_content(&files[0], b"content2").expect("Could not read file"));
+
+    let output = xch_with_stdin(&["--stdin", "-0k"], input.as_bytes());
+    assert_eq!(output.status.code(), Some(3));
+    assert!(util::ensure_file_content(&files[0], b"content1").expect("Could not read file"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("swapped: 1, failed: 1"));
+
+    let output = xch_with_stdin(&["--stdin"], b"only-one-path\n");
+    assert_eq!(output.status.code(), Some(2));
+    let output = xch_with_stdin(&["--stdin", "a", "b"], b"");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_safety_checks() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file = dir.path().join("file");
+    let sub = dir.path().join("sub");
+    util::create_file_with_content(&file, b"content").expect("Could not create file in tempdir");
+    fs::create_dir_all(sub.join("nested")).expect("Could not create directory in tempdir");
+    util::create_file_with_content(sub.join("nested/big"), &[0; 3 * 1024]).expect("Could not create file in tempdir");
+    let (file, sub) = (file.to_str().expect("UTF-8 path"), sub.to_str().expect("UTF-8 path"));
+
+    let output = xch().args([file, sub]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a directory"));
+    assert!(fs::metadata(file).expect("file exists").is_file());
+
+    let output = xch_with_stdin(&["-i", file, sub], b"n\n");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("file, 7 bytes"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("directory with 2 entries, 3.0 KiB in total"));
+    assert!(fs::metadata(file).expect("file exists").is_file());
+
+    let output = xch_with_stdin(&["-i", file, sub], b"y\n");
+    assert!(output.status.success());
+    assert!(fs::metadata(file).expect("file exists").is_dir());
+
+    let output = xch().args(["--force", file, sub]).output().expect("Could not run xch");
+    assert!(output.status.success());
+    assert!(fs::metadata(file).expect("file exists").is_file());
+
+    let root = if cfg!(windows) { "C:\\" } else { "/" };
+    let output = xch().arg(root).arg(sub).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is the root directory"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_cli_hooks() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    let seen = dir.path().join("seen");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    let output = xch().arg(&file1).arg(&file2)
+        .arg("--exec-before").arg(format!("echo \"$XCH_PATH1 $XCH_PATH2\" > {}", seen.display()))
+        .arg("--exec-after").arg("true")
+        .output().expect("Could not run xch");
+    assert!(output.status.success());
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+    let expected = format!("{} {}\n", file1.display(), file2.display());
+    assert!(util::ensure_file_content(&seen, expected.as_bytes()).expect("Could not read file"));
+
+    let output = xch().arg(&file1).arg(&file2).arg("--exec-before").arg("false").output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(9));
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    let output = xch().arg(&file1).arg(&file2).arg("--exec-after").arg("false").arg("--revert-on-failure").output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(6));
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_cli_verify() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    let output = xch().arg(&file1).arg(&file2).arg("--verify").arg("grep -q content2 \"$XCH_PATH1\"").output().expect("Could not run xch");
+    assert!(output.status.success());
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    let output = xch().arg(&file1).arg(&file2).args(["--verify", "false", "-o", "json"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(10));
+    let json = String::from_utf8_lossy(&output.stdout);
+    assert!(json.contains("\"success\":false"), "{}", json);
+    assert!(json.contains("\"revert_strategy\":\"atomic\""), "{}", json);
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    let output = xch().arg(&file1).arg(&file2).args(["--verify", "sleep 5", "--verify-timeout", "0.1"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(10));
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    let output = xch().arg(&file1).arg(&file2).args(["--verify-timeout", "1"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_watch() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let staged = dir.path().join("next");
+    let live = dir.path().join("current");
+    fs::create_dir(&staged).expect("Could not create directory in tempdir");
+    fs::create_dir(&live).expect("Could not create directory in tempdir");
+    util::create_file_with_content(staged.join("file"), b"new").expect("Could not create file in tempdir");
+
+    let mut child = xch().args(["watch", "--marker", "done"]).arg(&staged).arg(&live)
+        .stderr(Stdio::piped()).spawn().expect("Could not run xch");
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(child.try_wait().expect("Could not check xch").is_none());
+
+    util::create_file_with_content(staged.join("done"), b"").expect("Could not create file in tempdir");
+    let status = child.wait().expect("Could not wait for xch");
+    assert!(status.success());
+    assert!(util::ensure_file_content(live.join("file"), b"new").expect("Could not read file"));
+    assert!(!live.join("done").exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cli_watch_root() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let staged = dir.path().join("data/next");
+    let live = dir.path().join("srv/current");
+    fs::create_dir_all(&staged).expect("Could not create directory in tempdir");
+    fs::create_dir_all(&live).expect("Could not create directory in tempdir");
+    util::create_file_with_content(staged.join("file"), b"new").expect("Could not create file in tempdir");
+    // Only resolves to the staged directory beneath the root, not on the host.
+    std::os::unix::fs::symlink("/data", dir.path().join("stage")).expect("Could not create symlink in tempdir");
+
+    let mut child = xch().args(["watch", "--marker", "done", "--root"]).arg(dir.path()).args(["/stage/next", "/srv/current"])
+        .stderr(Stdio::piped()).spawn().expect("Could not run xch");
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(child.try_wait().expect("Could not check xch").is_none());
+
+    util::create_file_with_content(staged.join("done"), b"").expect("Could not create file in tempdir");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("Could not check xch") {
+            break status;
+        }
+        if std::time::Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("xch did not notice the marker beneath the root");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    assert!(status.success());
+    assert!(util::ensure_file_content(live.join("file"), b"new").expect("Could not read file"));
+    assert!(!live.join("done").exists() && !staged.join("done").exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cli_root() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let srv = dir.path().join("srv");
+    std::fs::create_dir(&srv).expect("Could not create directory in tempdir");
+    util::create_file_with_content(srv.join("file1"), b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(srv.join("file2"), b"content2").expect("Could not create file in tempdir");
+
+    let output = xch().arg("--root").arg(dir.path()).args(["/srv/file1", "/srv/file2"]).output().expect("Could not run xch");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(util::ensure_file_content(srv.join("file1"), b"content2").expect("Could not read file"));
+
+    let output = xch().arg("check").arg("--root").arg(dir.path()).args(["/srv", "/srv/file1"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(1));
+    let root = dir.path().to_str().expect("UTF-8 path");
+    let output = xch_with_stdin(&["--root", root, "-i", "/srv/file1", "/srv/file2"], b"n\n");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/srv/file1: file, 8 bytes"));
+    assert!(util::ensure_file_content(srv.join("file1"), b"content2").expect("Could not read file"));
+
+    util::create_file_with_content(srv.join("file3"), b"content3").expect("Could not create file in tempdir");
+    let output = xch().args(["rotate", "--root", root, "/srv/file1", "/srv/file2", "/srv/file3"]).output().expect("Could not run xch");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(util::ensure_file_content(srv.join("file1"), b"content1").expect("Could not read file"));
+    assert!(util::ensure_file_content(srv.join("file3"), b"content2").expect("Could not read file"));
+
+    std::os::unix::fs::symlink("/srv", dir.path().join("link")).expect("Could not create symlink in tempdir");
+    let output = xch().arg("--root").arg(dir.path()).args(["--no-symlinks", "/link/file1", "/link/file2"]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    let output = xch().arg("--no-symlinks").arg(dir.path().join("link/file1")).arg(dir.path().join("link/file2")).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    let output = xch().args(["rotate", "--no-symlinks"]).args([dir.path().join("link/file1"), dir.path().join("link/file2")]).output().expect("Could not run xch");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(util::ensure_file_content(srv.join("file1"), b"content1").expect("Could not read file"));
+}
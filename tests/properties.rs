@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    path,
+};
+
+use libxch::{Backend, Error, Native};
+use proptest::prelude::*;
+
+/// An entry of a generated file system tree.
+#[derive(Debug, Clone)]
+enum Entry {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Entry>),
+    Symlink(String),
+}
+
+/// The observed state of a single path, relative to the root of the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Observed {
+    File(Vec<u8>),
+    Dir,
+    Symlink(path::PathBuf),
+}
+
+type Snapshot = BTreeMap<path::PathBuf, Observed>;
+
+/// Backend that only supports the non-atomic fallback on the real file system.
+struct FallbackOnly;
+
+impl Backend for FallbackOnly {
+    fn exchange(&self, _path1: &path::Path, _path2: &path::Path) -> Result<(), Error> {
+        Err(Error::NotImplemented)
+    }
+
+    fn supports_exchange(&self) -> bool {
+        false
+    }
+
+    fn lookup(&self, path: &path::Path) -> io::Result<libxch::Entry> {
+        Native.lookup(path)
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn rename_noreplace(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        Native.rename_noreplace(from, to)
+    }
+
+    fn write_sidecar(&self, path: &path::Path, content: &[u8]) -> io::Result<()> {
+        Native.write_sidecar(path, content)
+    }
+
+    fn remove_sidecar(&self, path: &path::Path) -> io::Result<()> {
+        Native.remove_sidecar(path)
+    }
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["a", "b", "c", "d"]).prop_map(String::from)
+}
+
+fn tree() -> impl Strategy<Value = BTreeMap<String, Entry>> {
+    let leaf = prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..8).prop_map(Entry::File),
+        Just(Entry::Dir(BTreeMap::new())),
+        prop::sample::select(vec!["a", "b", "../a", "missing"]).prop_map(|t| Entry::Symlink(t.to_string())),
+    ];
+    let entry = leaf.prop_recursive(3, 16, 4, |inner| {
+        prop::collection::btree_map(name(), inner, 0..4).prop_map(Entry::Dir)
+    });
+    prop::collection::btree_map(name(), entry, 1..4)
+}
+
+fn create(base: &path::Path, entries: &BTreeMap<String, Entry>) -> io::Result<()> {
+    for (name, entry) in entries {
+        let path = base.join(name);
+        match entry {
+            Entry::File(content) => fs::write(&path, content)?,
+            Entry::Dir(children) => {
+                fs::create_dir(&path)?;
+                create(&path, children)?;
+            }
+            Entry::Symlink(target) => symlink(target, &path)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &path::Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, path: &path::Path) -> io::Result<()> {
+    fs::write(path, b"symlink")
+}
+
+fn snapshot(root: &path::Path) -> io::Result<Snapshot> {
+    fn walk(root: &path::Path, dir: &path::Path, out: &mut Snapshot) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let rel = path.strip_prefix(root).expect("walked path is below root").to_path_buf();
+            let meta = fs::symlink_metadata(&path)?;
+            if meta.file_type().is_symlink() {
+                out.insert(rel, Observed::Symlink(fs::read_link(&path)?));
+            } else if meta.is_dir() {
+                out.insert(rel, Observed::Dir);
+                walk(root, &path, out)?;
+            } else {
+                out.insert(rel, Observed::File(fs::read(&path)?));
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = Snapshot::new();
+    walk(root, root, &mut out)?;
+    Ok(out)
+}
+
+/// The snapshot we expect after successfully exchanging two unrelated paths.
+fn swapped(before: &Snapshot, a: &path::Path, b: &path::Path) -> Snapshot {
+    before.iter()
+        .map(|(p, o)| {
+            let p = if let Ok(rest) = p.strip_prefix(a) {
+                join(b, rest)
+            } else if let Ok(rest) = p.strip_prefix(b) {
+                join(a, rest)
+            } else {
+                p.clone()
+            };
+            (p, o.clone())
+        })
+        .collect()
+}
+
+fn join(base: &path::Path, rest: &path::Path) -> path::PathBuf {
+    if rest.as_os_str().is_empty() { base.to_path_buf() } else { base.join(rest) }
+}
+
+/// Exchange two paths of a generated tree and check that content was swapped or left unchanged.
+fn check_exchange<F>(entries: &BTreeMap<String, Entry>, a: &path::Path, b: &path::Path, exchange: F) -> Result<(), TestCaseError>
+    where F: Fn(&path::Path, &path::Path) -> Result<(), Error>
+{
+    let dir = tempdir::TempDir::new("prop").expect("Could not create temporary directory");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).expect("Could not create root directory");
+    create(&root, entries).expect("Could not create tree");
+
+    let before = snapshot(&root).expect("Could not read tree");
+    let result = exchange(&root.join(a), &root.join(b));
+    let after = snapshot(&root).expect("Could not read tree");
+
+    let nested = a != b && (a.starts_with(b) || b.starts_with(a));
+    if nested {
+        let is_nested = matches!(result, Err(Error::Nested { .. }));
+        prop_assert!(is_nested, "nested exchange of {:?} and {:?} returned {:?}", a, b, result);
+    }
+    if a == b {
+        prop_assert!(result.is_ok(), "exchange of {:?} with itself returned {:?}", a, result);
+    }
+    if result.is_ok() {
+        prop_assert_eq!(after, swapped(&before, a, b));
+    } else {
+        prop_assert_eq!(after, before);
+    }
+    Ok(())
+}
+
+/// A tree together with two paths in it.
+fn tree_and_paths() -> impl Strategy<Value = (BTreeMap<String, Entry>, path::PathBuf, path::PathBuf)> {
+    (tree(), any::<prop::sample::Index>(), any::<prop::sample::Index>()).prop_map(|(tree, i, j)| {
+        let mut paths = Vec::new();
+        fn collect(prefix: &path::Path, entries: &BTreeMap<String, Entry>, out: &mut Vec<path::PathBuf>) {
+            for (name, entry) in entries {
+                let path = prefix.join(name);
+                if let Entry::Dir(children) = entry {
+                    collect(&path, children, out);
+                }
+                out.push(path);
+            }
+        }
+        collect(path::Path::new(""), &tree, &mut paths);
+        let a = i.get(&paths).clone();
+        let b = j.get(&paths).clone();
+        (tree, a, b)
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn prop_xch_swaps_or_preserves((tree, a, b) in tree_and_paths()) {
+        check_exchange(&tree, &a, &b, |p1, p2| libxch::xch(p1, p2))?;
+    }
+
+    #[test]
+    fn prop_xch_non_atomic_swaps_or_preserves((tree, a, b) in tree_and_paths()) {
+        check_exchange(&tree, &a, &b, |p1, p2| libxch::xch_non_atomic(p1, p2))?;
+    }
+
+    #[test]
+    fn prop_fallback_swaps_or_preserves((tree, a, b) in tree_and_paths()) {
+        check_exchange(&tree, &a, &b, |p1, p2| libxch::xch_non_atomic_with(&FallbackOnly, p1, p2))?;
+    }
+}
@@ -0,0 +1,171 @@
+use std::path;
+
+use libxch::testing::{FakeFs, Node, Operation};
+use libxch::{Error, ErrorKind, Rollback};
+
+const EXDEV: i32 = 18;
+const EACCES: i32 = 13;
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+
+fn setup() -> FakeFs {
+    let fs = FakeFs::without_exchange();
+    fs.create_dir("dir").expect("Could not create directory");
+    fs.create_file("dir/file", b"content1").expect("Could not create file");
+    fs.create_file("file", b"content2").expect("Could not create file");
+    fs
+}
+
+/// The content of the file system, ignoring where it is located.
+fn contents(fs: &FakeFs) -> Vec<Node> {
+    let mut nodes: Vec<_> = fs.entries().into_iter().map(|(_, n)| n).collect();
+    nodes.sort_by_key(|n| format!("{:?}", n));
+    nodes
+}
+
+fn temp_path(fs: &FakeFs) -> path::PathBuf {
+    match fs.operations().into_iter().find(|op| matches!(op, Operation::Rename(_, _))) {
+        Some(Operation::Rename(_, to)) => to,
+        _ => panic!("no rename recorded"),
+    }
+}
+
+#[test]
+fn test_rename_failure_rolls_back() {
+    for step in 1..=3 {
+        let fs = setup();
+        let before = fs.entries();
+        fs.fail_rename(step, EXDEV);
+
+        // Only failing to claim the temporary name leaves nothing to roll back.
+        let err = libxch::xch_non_atomic_with(&fs, "dir", "file").expect_err("exchange should fail");
+        match (step, &err) {
+            (1, Error::Fs(_)) => assert_eq!(err.rollback(), Rollback::NotNeeded),
+            (_, Error::RolledBack(_)) => assert_eq!(err.rollback(), Rollback::Complete),
+            _ => panic!("unexpected error for step {}: {:?}", step, err),
+        }
+        assert_eq!(err.raw_os_error(), Some(EXDEV), "step {}", step);
+        assert_eq!(err.kind(), ErrorKind::CrossDevice, "step {}", step);
+        assert_eq!(fs.entries(), before, "step {}", step);
+        let reverts = fs.operations().iter().filter(|op| matches!(op, Operation::Revert(_, _))).count();
+        assert_eq!(reverts, step - 1, "step {}", step);
+    }
+}
+
+#[test]
+fn test_rollback_failure_chains_both_causes() {
+    for step in 2..=3 {
+        for revert in 1..step {
+            let fs = setup();
+            let before = contents(&fs);
+            fs.fail_rename(step, EXDEV);
+            fs.fail_revert(revert, EACCES);
+
+            let err = libxch::xch_non_atomic_with(&fs, "dir", "file").expect_err("exchange should fail");
+            match err {
+                Error::ChainError(ref rollback, ref cause) => {
+                    assert_eq!(rollback.raw_os_error(), Some(EACCES));
+                    assert_eq!(cause.raw_os_error(), Some(EXDEV));
+                }
+                ref other => panic!("unexpected error for step {}, revert {}: {:?}", step, revert, other),
+            }
+            assert_eq!(err.rollback(), Rollback::Failed);
+            assert_eq!(err.raw_os_error(), Some(EXDEV));
+            let message = err.to_string();
+            assert!(message.contains(&::std::io::Error::from_raw_os_error(EXDEV).to_string()), "{}", message);
+            assert!(message.contains(&::std::io::Error::from_raw_os_error(EACCES).to_string()), "{}", message);
+
+            // Nothing may get lost, even if the rollback failed.
+            assert_eq!(contents(&fs), before, "step {}, revert {}", step, revert);
+        }
+    }
+}
+
+#[test]
+fn test_rollback_failure_known_state() {
+    // Failing the second rename and its rollback leaves the first path at the temporary name.
+    let fs = setup();
+    fs.fail_rename(2, EXDEV);
+    fs.fail_revert(1, EACCES);
+    assert!(libxch::xch_non_atomic_with(&fs, "dir", "file").is_err());
+    let temp = temp_path(&fs);
+    assert!(!fs.exists("dir"));
+    assert_eq!(fs.get(&temp), Some(Node::Dir));
+    assert_eq!(fs.read_file(temp.join("file")), Some(b"content1".to_vec()));
+    assert_eq!(fs.read_file("file"), Some(b"content2".to_vec()));
+
+    // Failing the third rename and the second revert leaves the second path in place of the first.
+    let fs = setup();
+    fs.fail_rename(3, EXDEV);
+    fs.fail_revert(2, EACCES);
+    assert!(libxch::xch_non_atomic_with(&fs, "dir", "file").is_err());
+    let temp = temp_path(&fs);
+    assert_eq!(fs.read_file("file"), Some(b"content2".to_vec()));
+    assert!(!fs.exists("dir"));
+    assert_eq!(fs.read_file(temp.join("file")), Some(b"content1".to_vec()));
+}
+
+#[test]
+fn test_atomic_failure_does_not_fall_back() {
+    let fs = FakeFs::new();
+    fs.create_file("a", b"content1").expect("Could not create file");
+    fs.create_file("b", b"content2").expect("Could not create file");
+    fs.fail_exchange(EXDEV);
+
+    let err = libxch::xch_non_atomic_with(&fs, "a", "b").expect_err("exchange should fail");
+    assert_eq!(err.raw_os_error(), Some(EXDEV));
+    assert_eq!(fs.operations(), vec![Operation::Exchange("a".into(), "b".into())]);
+    assert_eq!(fs.read_file("a"), Some(b"content1".to_vec()));
+    assert_eq!(fs.read_file("b"), Some(b"content2".to_vec()));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_unsupported_exchange_falls_back() {
+    let fs = FakeFs::new();
+    fs.create_file("a", b"content1").expect("Could not create file");
+    fs.create_file("b", b"content2").expect("Could not create file");
+    // renameat2 fails with EINVAL on file systems without RENAME_EXCHANGE.
+    fs.fail_exchange(EINVAL);
+
+    let err = libxch::xch_with(&fs, "a", "b").expect_err("exchange should fail");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert_eq!(fs.read_file("a"), Some(b"content1".to_vec()));
+
+    let strategy = libxch::Options::new().non_atomic(true).xch_with(&fs, "a", "b").expect("fallback should succeed");
+    assert_eq!(strategy, libxch::Strategy::NonAtomic);
+    assert_eq!(fs.read_file("a"), Some(b"content2".to_vec()));
+    assert_eq!(fs.read_file("b"), Some(b"content1".to_vec()));
+}
+
+/// Exchange errors of `renameat2`, injected into the atomic path of `Options::xch_with`.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_exchange_errno() {
+    for &(errno, kind, falls_back) in &[
+        (EINVAL, ErrorKind::Unsupported, true),
+        (ENOSYS, ErrorKind::Unsupported, true),
+        (EXDEV, ErrorKind::CrossDevice, false),
+    ] {
+        let fs = FakeFs::new();
+        fs.create_file("a", b"content1").expect("Could not create file");
+        fs.create_file("b", b"content2").expect("Could not create file");
+        fs.fail_exchange(errno);
+
+        let err = libxch::Options::new().xch_with(&fs, "a", "b").expect_err("exchange should fail");
+        assert!(matches!(err, Error::PlatformError(_)), "{:?}", err);
+        assert_eq!(err.raw_os_error(), Some(errno));
+        assert_eq!(err.kind(), kind, "errno {}", errno);
+        assert_eq!(err.rollback(), Rollback::NotNeeded);
+
+        let res = libxch::Options::new().non_atomic(true).xch_with(&fs, "a", "b");
+        if falls_back {
+            assert_eq!(res.expect("fallback should succeed"), libxch::Strategy::NonAtomic);
+            assert_eq!(fs.read_file("a"), Some(b"content2".to_vec()));
+        } else {
+            assert_eq!(res.expect_err("exchange should fail").kind(), kind);
+            assert_eq!(fs.read_file("a"), Some(b"content1".to_vec()));
+            assert!(fs.operations().iter().all(|op| matches!(op, Operation::Exchange(_, _))));
+        }
+    }
+}
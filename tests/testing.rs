@@ -0,0 +1,290 @@
+use std::error::Error as _;
+use std::path::{Path, PathBuf};
+
+use libxch::testing::{FakeFs, Node, Operation};
+use libxch::{Error, ErrorKind, FileType};
+
+fn setup(fs: &FakeFs) {
+    fs.create_dir("dir").expect("Could not create directory");
+    fs.create_file("dir/file", b"content1").expect("Could not create file");
+    fs.create_file("file", b"content2").expect("Could not create file");
+}
+
+#[test]
+fn test_fake_exchange() {
+    let fs = FakeFs::new();
+    setup(&fs);
+
+    assert!(libxch::xch_with(&fs, "dir", "file").is_ok());
+    assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+    assert_eq!(fs.read_file("file/file"), Some(b"content1".to_vec()));
+}
+
+#[test]
+fn test_fake_fallback() {
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+
+    assert!(matches!(libxch::xch_with(&fs, "dir", "file"), Err(Error::NotImplemented)));
+    assert!(libxch::xch_non_atomic_with(&fs, "dir", "file").is_ok());
+    assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+    assert_eq!(fs.read_file("file/file"), Some(b"content1".to_vec()));
+    assert_eq!(fs.entries().len(), 3);
+}
+
+#[test]
+fn test_fake_rename_fault_rolls_back() {
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+    let before = fs.entries();
+    fs.fail_rename(3, 18);
+
+    assert!(matches!(libxch::xch_non_atomic_with(&fs, "dir", "file"), Err(Error::RolledBack(_))));
+    assert_eq!(fs.entries(), before);
+}
+
+#[test]
+fn test_fake_revert_fault_chains_errors() {
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+    fs.fail_rename(3, 18);
+    fs.fail_revert(1, 13);
+
+    assert!(matches!(libxch::xch_non_atomic_with(&fs, "dir", "file"), Err(Error::ChainError(_, _))));
+    assert!(!fs.exists("file"));
+}
+
+#[test]
+fn test_fake_nested_is_rejected_before_renames() {
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+
+    assert!(matches!(libxch::xch_non_atomic_with(&fs, "dir/file", "dir"), Err(Error::Nested { .. })));
+    assert!(libxch::xch_non_atomic_with(&fs, "dir", "dir").is_ok());
+    assert!(fs.operations().is_empty());
+}
+
+#[test]
+fn test_fake_staging_dir() {
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+    fs.create_dir("staging").expect("Could not create directory");
+    fs.fail_rename(2, 18);
+
+    let options = libxch::Options::new().non_atomic(true).staging_dir("staging");
+    assert!(options.xch_with(&fs, "dir", "file").is_err());
+    match fs.operations().get(1) {
+        Some(Operation::Rename(from, to)) => {
+            assert_eq!(from, &PathBuf::from("dir"));
+            assert_eq!(to.parent(), Some(Path::new("staging")));
+            assert!(to.file_name().unwrap().to_string_lossy().starts_with(".xch-tmp-"));
+        }
+        other => panic!("unexpected operation: {:?}", other),
+    }
+    assert_eq!(fs.get("dir"), Some(Node::Dir));
+}
+
+#[test]
+fn test_fake_default_staging_dir() {
+    let temp_parent = |fs: &FakeFs| match fs.operations().get(1) {
+        Some(Operation::Rename(_, to)) => to.parent().map(Path::to_path_buf),
+        other => panic!("unexpected operation: {:?}", other),
+    };
+
+    // Next to the first path, in the current directory for a single component.
+    let fs = FakeFs::without_exchange();
+    setup(&fs);
+    fs.create_dir("srv").expect("Could not create directory");
+    fs.create_file("srv/next", b"next").expect("Could not create file");
+    assert!(libxch::xch_non_atomic_with(&fs, "file", "srv/next").is_ok());
+    assert_eq!(temp_parent(&fs), Some(PathBuf::new()));
+
+    // Next to the second path if the first one is a root directory.
+    let fs = FakeFs::without_exchange();
+    fs.create_dir("/").expect("Could not create directory");
+    fs.create_dir("srv").expect("Could not create directory");
+    fs.create_file("srv/next", b"next").expect("Could not create file");
+    assert!(libxch::xch_non_atomic_with(&fs, "/", "srv/next").is_ok());
+    assert_eq!(temp_parent(&fs), Some(PathBuf::from("srv")));
+    assert_eq!(fs.read_file("/"), Some(b"next".to_vec()));
+    assert_eq!(fs.get("srv/next"), Some(Node::Dir));
+}
+
+#[test]
+fn test_fake_special_files_and_mount_points() {
+    let fs = FakeFs::new();
+    setup(&fs);
+    fs.create_special("fifo", FileType::Fifo).expect("Could not create FIFO");
+    fs.create_dir("mnt").expect("Could not create directory");
+    fs.mount("mnt").expect("Could not mount directory");
+
+    match libxch::xch_with(&fs, "file", "fifo") {
+        Err(Error::SpecialFile { ref path, file_type: FileType::Fifo }) => assert_eq!(path, Path::new("fifo")),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(matches!(libxch::xch_with(&fs, "mnt", "dir"), Err(Error::MountPoint(_))));
+    assert!(fs.operations().is_empty());
+
+    let options = libxch::Options::new().allow_special_files(true).allow_mount_points(true);
+    assert!(options.xch_with(&fs, "file", "fifo").is_ok());
+    assert_eq!(fs.get("file"), Some(Node::Special(FileType::Fifo)));
+    let err = options.xch_with(&fs, "mnt", "dir").expect_err("renaming a mount point fails");
+    assert_eq!(err.kind(), ErrorKind::Busy);
+}
+
+#[test]
+fn test_fake_hooks() {
+    use std::sync::{Arc, Mutex};
+
+    let fs = FakeFs::new();
+    setup(&fs);
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let log = |name: &'static str| {
+        let calls = calls.clone();
+        move |_: &Path, _: &Path| calls.lock().unwrap().push(name)
+    };
+
+    let (before, after, rollback) = (log("before"), log("after"), log("rollback"));
+    let options = libxch::Options::new()
+        .on_before(move |p1, p2| { before(p1, p2); Ok(()) })
+        .on_after(move |p1, p2| { after(p1, p2); Err("reload failed".into()) })
+        .on_rollback(rollback);
+    let err = options.xch_with(&fs, "dir", "file").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Hook);
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+    assert_eq!(*calls.lock().unwrap(), ["before", "after"]);
+
+    calls.lock().unwrap().clear();
+    let err = options.revert_on_hook_failure(true).xch_with(&fs, "dir", "file").unwrap_err();
+    assert!(matches!(err, Error::RolledBack(ref e) if matches!(**e, Error::Hook { hook: "after", .. })));
+    assert_eq!(err.source().map(ToString::to_string).as_deref(), Some("reload failed"));
+    assert!(err.to_string().contains("after the exchange: reload failed"), "{}", err);
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+    assert_eq!(*calls.lock().unwrap(), ["before", "after", "rollback"]);
+
+    let options = libxch::Options::new().on_before(|_, _| Err("service busy".into()));
+    assert!(matches!(options.xch_with(&fs, "dir", "file"), Err(Error::Hook { hook: "before", .. })));
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+}
+
+#[test]
+fn test_fake_verified_exchange() {
+    let fs = FakeFs::new();
+    setup(&fs);
+
+    let options = libxch::Options::new();
+    let verification = options.xch_verified_with(&fs, "dir", "file", |_, _| Ok(())).expect("Could not exchange");
+    assert!(verification.passed());
+    assert_eq!(verification.revert, None);
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+
+    let verification = options.xch_verified_with(&fs, "dir", "file", |path1, _| {
+        assert_eq!(fs.get(path1), Some(Node::Dir));
+        Err("health check failed".into())
+    }).expect("Could not exchange");
+    assert!(!verification.passed());
+    assert_eq!(verification.exchange, libxch::Strategy::Atomic);
+    assert_eq!(verification.revert, Some(libxch::Strategy::Atomic));
+    assert_eq!(fs.get("file"), Some(Node::Dir));
+}
+
+#[test]
+#[cfg(feature = "checksum")]
+fn test_fake_checksum_mismatch_rolls_back() {
+    let options = libxch::Options::new().non_atomic(true).verify_checksums(true);
+    for fs in &[FakeFs::new(), FakeFs::without_exchange()] {
+        setup(fs);
+        assert!(options.xch_with(fs, "dir", "file").is_ok());
+        assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+
+        fs.corrupt("dir/file", b"tampered");
+        let err = options.xch_with(fs, "dir", "file").unwrap_err();
+        assert!(matches!(err, Error::RolledBack(ref e) if e.kind() == ErrorKind::ChecksumMismatch), "{:?}", err);
+        assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+        assert_eq!(fs.read_file("file/file"), Some(b"tampered".to_vec()));
+    }
+}
+
+#[test]
+#[cfg(feature = "checksum")]
+fn test_fake_rotate_rolls_back_once() {
+    let fs = FakeFs::new();
+    for dir in &["a", "b", "c"] {
+        fs.create_dir(dir).unwrap();
+    }
+    fs.create_file("c/file", b"content").unwrap();
+
+    // The second exchange fails its verification and is undone, then the first one is reverted.
+    fs.corrupt("b/file", b"tampered");
+    let err = libxch::Options::new().verify_checksums(true).rotate_with(&fs, &["a", "b", "c"]).unwrap_err();
+    assert!(matches!(err, Error::RolledBack(ref e) if matches!(**e, Error::ChecksumMismatch(_))), "{:?}", err);
+    assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+    assert_eq!(fs.read_file("c/file"), Some(b"tampered".to_vec()));
+    assert!(!fs.exists("a/file") && !fs.exists("b/file"));
+}
+
+#[test]
+fn test_fake_rotate_first_pair_rolled_back() {
+    let fs = FakeFs::without_exchange();
+    for name in &["a", "b", "c"] {
+        fs.create_file(name, name.as_bytes()).unwrap();
+    }
+
+    // The last rename of the first fallback fails, nothing else was changed before it.
+    fs.fail_rename(3, 18);
+    let err = libxch::Options::new().non_atomic(true).rotate_with(&fs, &["a", "b", "c"]).unwrap_err();
+    assert!(matches!(err, Error::RolledBack(ref e) if matches!(**e, Error::Fs(_))), "{:?}", err);
+    assert_eq!(err.rollback(), libxch::Rollback::Complete);
+    for name in &["a", "b", "c"] {
+        assert_eq!(fs.read_file(name), Some(name.as_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn test_fake_secure_refuses_unknown_owners() {
+    let fs = FakeFs::new();
+    setup(&fs);
+
+    let err = libxch::Options::new().secure(true).xch_with(&fs, "dir", "file").unwrap_err();
+    assert!(matches!(err, Error::Insecure { .. }), "{:?}", err);
+    assert_eq!(fs.get("dir"), Some(Node::Dir));
+}
+
+#[test]
+fn test_fake_preserve_labels() {
+    use libxch::Backend;
+    use std::sync::{Arc, Mutex};
+
+    let fs = FakeFs::new();
+    setup(&fs);
+    fs.set_label(Path::new("dir"), "security.selinux", Some(b"httpd_sys_content_t")).unwrap();
+    fs.set_label(Path::new("file"), "security.selinux", Some(b"user_home_t")).unwrap();
+    fs.set_label(Path::new("file"), "system.posix_acl_access", Some(b"acl")).unwrap();
+    let (dir_labels, file_labels) = (fs.labels(Path::new("dir")).unwrap(), fs.labels(Path::new("file")).unwrap());
+
+    let relabeled = Arc::new(Mutex::new(Vec::new()));
+    let report = relabeled.clone();
+    let options = libxch::Options::new()
+        .preserve_labels(true)
+        .on_relabel(move |path, changed| report.lock().unwrap().push((path.to_path_buf(), changed.to_vec())));
+    assert!(options.xch_with(&fs, "dir", "file").is_ok());
+    assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+    assert_eq!(fs.labels(Path::new("dir")).unwrap(), dir_labels);
+    assert_eq!(fs.labels(Path::new("file")).unwrap(), file_labels);
+    assert_eq!(fs.labels(Path::new("file/file")).unwrap(), libxch::Labels::new());
+    let changed = vec!["security.selinux".to_string(), "system.posix_acl_access".to_string()];
+    assert_eq!(*relabeled.lock().unwrap(), vec![(PathBuf::from("dir"), changed.clone()), (PathBuf::from("file"), changed)]);
+
+    // Relabeling "dir" succeeds, "file" fails: "dir" gets its old labels back before the rollback.
+    fs.fail_set_label("file", 13);
+    let err = options.xch_with(&fs, "dir", "file").unwrap_err();
+    assert!(matches!(err, Error::RolledBack(ref e) if matches!(**e, Error::Relabel { ref path, .. } if path == Path::new("file"))), "{:?}", err);
+    assert_eq!(err.source().and_then(|e| e.downcast_ref::<std::io::Error>()).and_then(std::io::Error::raw_os_error), Some(13));
+    assert!(err.to_string().contains("labels of file: "), "{}", err);
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(fs.read_file("dir"), Some(b"content2".to_vec()));
+    assert_eq!(fs.labels(Path::new("dir")).unwrap(), dir_labels);
+    assert_eq!(fs.labels(Path::new("file")).unwrap(), file_labels);
+    assert_eq!(relabeled.lock().unwrap().len(), 2);
+}
@@ -1,5 +1,3 @@
-use tempdir;
-use libxch;
 mod util;
 
 #[test]
@@ -24,3 +22,467 @@ fn test_failure() {
     assert!(libxch::xch_non_atomic(&file1, dir.path()).is_err());
     assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_atomic_errno() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let missing = dir.path().join("missing");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+
+    // ENOENT
+    assert_eq!(libxch::xch(&file1, &missing).expect_err("exchange should fail").raw_os_error(), Some(2));
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_same_path() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+
+    assert!(libxch::xch_non_atomic(&file1, &file1).is_ok());
+    assert!(libxch::xch_non_atomic(&file1, dir.path().join(".").join("file1")).is_ok());
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+    assert_eq!(::std::fs::read_dir(dir.path()).expect("Could not read tempdir").count(), 1);
+}
+
+#[test]
+fn test_hard_links() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    ::std::fs::hard_link(&file1, &file2).expect("Could not create hard link");
+
+    assert!(libxch::xch_non_atomic(&file1, &file2).is_ok());
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+    assert!(util::ensure_file_content(&file2, b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_nested() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let sub = dir.path().join("sub");
+    let file1 = sub.join("file1");
+    ::std::fs::create_dir(&sub).expect("Could not create directory in tempdir");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+
+    match libxch::xch_non_atomic(&sub, &file1) {
+        Err(libxch::Error::Nested { ancestor, descendant }) => {
+            assert_eq!(ancestor, sub);
+            assert_eq!(descendant, file1);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match libxch::xch(&file1, dir.path()) {
+        Err(libxch::Error::Nested { ancestor, descendant }) => {
+            assert_eq!(ancestor, dir.path());
+            assert_eq!(descendant, file1);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+}
+
+#[test]
+fn test_staging_dir() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let staging = dir.path().join("staging");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    ::std::fs::create_dir(&staging).expect("Could not create directory in tempdir");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    let options = libxch::Options::new().non_atomic(true).staging_dir(&staging);
+    assert!(options.xch(&file1, &file2).is_ok());
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(&file2, b"content1").expect("Could not read file"));
+    assert_eq!(::std::fs::read_dir(&staging).expect("Could not read staging directory").count(), 0);
+}
+
+#[test]
+fn test_scan_orphans() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let orphan = dir.path().join(".xch-tmp-1");
+    let stray = dir.path().join(".xch-tmp-2");
+    let origin = dir.path().join("origin");
+    ::std::fs::create_dir(&orphan).expect("Could not create directory in tempdir");
+    util::create_file_with_content(orphan.join("file"), b"content1").expect("Could not create file in tempdir");
+    let sidecar = format!("origin={}\ntarget={}\n", origin.display(), dir.path().join("target").display());
+    util::create_file_with_content(dir.path().join(".xch-tmp-1.origin"), sidecar.as_bytes()).expect("Could not create file in tempdir");
+    util::create_file_with_content(&stray, b"content2").expect("Could not create file in tempdir");
+    util::create_file_with_content(dir.path().join("unrelated"), b"content3").expect("Could not create file in tempdir");
+
+    let orphans = libxch::scan_orphans(dir.path()).expect("Could not scan for orphans");
+    assert_eq!(orphans.len(), 2);
+    assert_eq!(orphans[0].path, orphan);
+    assert_eq!(orphans[0].origin.as_ref(), Some(&origin));
+    assert_eq!(orphans[0].size, 8);
+    assert_eq!(orphans[1].path, stray);
+    assert!(orphans[1].origin.is_none());
+
+    orphans[0].restore().expect("Could not restore orphan");
+    orphans[1].remove().expect("Could not remove orphan");
+    assert!(util::ensure_file_content(origin.join("file"), b"content1").expect("Could not read file"));
+    assert!(libxch::scan_orphans(dir.path()).expect("Could not scan for orphans").is_empty());
+    assert_eq!(::std::fs::read_dir(dir.path()).expect("Could not read tempdir").count(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_relink() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file1 = dir.path().join("file1");
+    let file2 = dir.path().join("file2");
+    let link = dir.path().join("link");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+
+    libxch::relink(&link, &file1).expect("Could not create link");
+    assert!(util::ensure_file_content(&link, b"content1").expect("Could not read file"));
+    libxch::relink(&link, &file2).expect("Could not change link");
+    assert!(util::ensure_file_content(&link, b"content2").expect("Could not read file"));
+
+    assert!(libxch::relink(&file1, &file2).is_err());
+    assert!(util::ensure_file_content(&file1, b"content1").expect("Could not read file"));
+    assert_eq!(::std::fs::read_dir(dir.path()).expect("Could not read tempdir").count(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_special_files() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let file = dir.path().join("file");
+    let socket = dir.path().join("socket");
+    util::create_file_with_content(&file, b"content").expect("Could not create file in tempdir");
+    let _listener = ::std::os::unix::net::UnixListener::bind(&socket).expect("Could not create socket");
+
+    match libxch::xch(&file, &socket) {
+        Err(libxch::Error::SpecialFile { file_type: libxch::FileType::Socket, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(util::ensure_file_content(&file, b"content").expect("Could not read file"));
+
+    let options = libxch::Options::new().allow_special_files(true);
+    assert!(options.xch(&file, &socket).is_ok());
+    assert!(util::ensure_file_content(&socket, b"content").expect("Could not read file"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_xch_files() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let path1 = dir.path().join("file1");
+    let path2 = dir.path().join("file2");
+    util::create_file_with_content(&path1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&path2, b"longer content2").expect("Could not create file in tempdir");
+    let open = |p: &::std::path::Path| ::std::fs::OpenOptions::new().read(true).write(true).open(p).expect("Could not open file");
+    let (mut file1, file2) = (open(&path1), open(&path2));
+
+    assert!(libxch::xch_files(&file1, &file2).is_ok());
+    assert!(util::ensure_file_content(&path1, b"longer content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(&path2, b"content1").expect("Could not read file"));
+
+    // The paths are swapped again, but file1 still refers to the file now at path2.
+    assert!(libxch::swap_contents(&file1, &file2).is_ok());
+    assert!(util::ensure_file_content(&path2, b"longer content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(&path1, b"content1").expect("Could not read file"));
+    let mut content = Vec::new();
+    file1.seek(SeekFrom::Start(0)).expect("Could not seek");
+    file1.read_to_end(&mut content).expect("Could not read file");
+    assert_eq!(content, b"longer content2");
+
+    ::std::fs::remove_file(&path1).expect("Could not remove file");
+    assert!(libxch::xch_files(&file1, &file2).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_publish() {
+    use std::{fs, io::Write, os::unix::fs::PermissionsExt};
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let target = dir.path().join("config");
+
+    let written = libxch::publish(dir.path(), "config", |file| file.write_all(b"version1").map(|_| 8));
+    assert_eq!(written.expect("Could not publish new file"), 8);
+    assert!(util::ensure_file_content(&target, b"version1").expect("Could not read file"));
+
+    fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).expect("Could not set permissions");
+    libxch::publish(dir.path(), "config", |file| file.write_all(b"version2")).expect("Could not replace file");
+    assert!(util::ensure_file_content(&target, b"version2").expect("Could not read file"));
+    let mode = fs::metadata(&target).expect("Could not read metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+
+    let failed = libxch::publish(dir.path(), "config", |file| {
+        file.write_all(b"partial")?;
+        Err::<(), _>(::std::io::Error::other("write failed"))
+    });
+    assert!(failed.is_err());
+    assert!(util::ensure_file_content(&target, b"version2").expect("Could not read file"));
+    assert_eq!(fs::read_dir(dir.path()).expect("Could not list directory").count(), 1);
+}
+
+#[test]
+fn test_stage() {
+    use std::fs;
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let live = dir.path().join("live");
+    fs::create_dir_all(live.join("sub")).expect("Could not create directory in tempdir");
+    util::create_file_with_content(live.join("file"), b"old").expect("Could not create file in tempdir");
+    util::create_file_with_content(live.join("sub/kept"), b"kept").expect("Could not create file in tempdir");
+
+    let mut stage = libxch::stage(&live).expect("Could not create stage");
+    assert_eq!(stage.path().parent(), Some(dir.path()));
+    let mut copied: Vec<_> = stage.copies().iter().map(|(path, _)| path.clone()).collect();
+    copied.sort();
+    assert_eq!(copied, vec![std::path::PathBuf::from("file"), std::path::Path::new("sub").join("kept")]);
+    assert!(util::ensure_file_content(stage.path().join("sub/kept"), b"kept").expect("Could not read file"));
+    util::create_file_with_content(stage.path().join("file"), b"new").expect("Could not write staged file");
+    assert!(util::ensure_file_content(live.join("file"), b"old").expect("Could not read file"));
+
+    stage.commit().expect("Could not commit stage");
+    assert!(stage.commit().is_err());
+    assert!(util::ensure_file_content(live.join("file"), b"new").expect("Could not read file"));
+    assert!(util::ensure_file_content(live.join("sub/kept"), b"kept").expect("Could not read file"));
+    assert!(util::ensure_file_content(stage.path().join("file"), b"old").expect("Could not read file"));
+
+    let old = stage.path().to_path_buf();
+    stage.discard().expect("Could not discard stage");
+    assert!(!old.exists());
+    assert_eq!(fs::read_dir(dir.path()).expect("Could not list directory").count(), 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_stage_symlink() {
+    use std::fs;
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let release = dir.path().join("release");
+    let live = dir.path().join("live");
+    fs::create_dir(&release).expect("Could not create directory in tempdir");
+    util::create_file_with_content(release.join("file"), b"old").expect("Could not create file in tempdir");
+    std::os::unix::fs::symlink(&release, &live).expect("Could not create symlink in tempdir");
+
+    assert!(libxch::stage(&live).is_err());
+    assert_eq!(fs::read_dir(dir.path()).expect("Could not list directory").count(), 2);
+    let stage = libxch::stage(&release).expect("Could not create stage");
+    assert!(!fs::symlink_metadata(stage.path()).expect("Could not read stage").file_type().is_symlink());
+}
+
+#[test]
+fn test_clone_file() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let from = dir.path().join("from");
+    let to = dir.path().join("to");
+    util::create_file_with_content(&from, b"content").expect("Could not create file in tempdir");
+
+    let method = libxch::clone_file(&from, &to).expect("Could not clone file");
+    assert!(util::ensure_file_content(&to, b"content").expect("Could not read file"));
+    if !cfg!(target_os = "linux") {
+        assert_eq!(method, libxch::CloneMethod::Copy);
+    }
+    assert!(libxch::clone_file(&from, &to).is_err());
+}
+
+#[test]
+fn test_wait_for() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let staged = dir.path().join("staged");
+    let marker = staged.join(".ready");
+
+    let waiter = {
+        let marker = marker.clone();
+        std::thread::spawn(move || libxch::wait_for(marker))
+    };
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    std::fs::create_dir(&staged).expect("Could not create directory in tempdir");
+    util::create_file_with_content(&marker, b"").expect("Could not create file in tempdir");
+    waiter.join().expect("Waiting thread panicked").expect("Could not wait for marker");
+
+    libxch::wait_for(&marker).expect("Could not wait for existing marker");
+    assert!(libxch::wait_for(dir.path().join("missing/sub/.ready")).is_err());
+}
+
+#[test]
+#[cfg(feature = "checksum")]
+fn test_digest() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let live = dir.path().join("live");
+    std::fs::create_dir_all(live.join("sub")).expect("Could not create directory in tempdir");
+    util::create_file_with_content(live.join("sub/file"), b"content").expect("Could not create file in tempdir");
+    let stage = libxch::stage(&live).expect("Could not create stage");
+
+    let digest = libxch::digest(&live).expect("Could not compute digest");
+    assert_eq!(libxch::digest(stage.path()).expect("Could not compute digest"), digest);
+    assert_ne!(libxch::digest(live.join("sub/file")).expect("Could not compute digest"), digest);
+    util::create_file_with_content(stage.path().join("sub/file"), b"changed").expect("Could not write staged file");
+    assert_ne!(libxch::digest(stage.path()).expect("Could not compute digest"), digest);
+
+    let options = libxch::Options::new().verify_checksums(true);
+    options.xch(&live, stage.path()).expect("Could not exchange");
+    assert_eq!(libxch::digest(stage.path()).expect("Could not compute digest"), digest);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_rooted() {
+    use std::fs;
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("srv")).expect("Could not create directory in tempdir");
+    util::create_file_with_content(root.join("srv/file1"), b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(root.join("srv/file2"), b"content2").expect("Could not create file in tempdir");
+    util::create_file_with_content(dir.path().join("outside"), b"outside").expect("Could not create file in tempdir");
+    std::os::unix::fs::symlink("/srv", root.join("link")).expect("Could not create symlink in tempdir");
+
+    let rooted = libxch::Rooted::open(&root).expect("Could not open root");
+    let options = libxch::Options::new();
+    assert_eq!(options.xch_with(&rooted, "/link/file1", "../../srv/file2").expect("Could not exchange"), libxch::Strategy::Atomic);
+    assert!(util::ensure_file_content(root.join("srv/file1"), b"content2").expect("Could not read file"));
+    assert!(util::ensure_file_content(root.join("srv/file2"), b"content1").expect("Could not read file"));
+
+    let err = options.xch_with(&rooted, "/srv/file1", "../outside").expect_err("path outside of the root was resolved");
+    assert_eq!(err.kind(), libxch::ErrorKind::NotFound);
+    assert!(util::ensure_file_content(dir.path().join("outside"), b"outside").expect("Could not read file"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_no_symlinks() {
+    use std::{
+        fs,
+        sync::{atomic::{AtomicBool, Ordering}, Arc},
+    };
+
+    let tempdir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let dir = tempdir.path().canonicalize().expect("Could not resolve tempdir");
+    for sub in &["shared", "outside"] {
+        fs::create_dir(dir.join(sub)).expect("Could not create directory in tempdir");
+        util::create_file_with_content(dir.join(sub).join("file1"), b"content1").expect("Could not create file in tempdir");
+        util::create_file_with_content(dir.join(sub).join("file2"), b"content2").expect("Could not create file in tempdir");
+    }
+    std::os::unix::fs::symlink(dir.join("outside"), dir.join("link")).expect("Could not create symlink in tempdir");
+
+    let hardened = libxch::Rooted::hardened().expect("Could not open hardened backend");
+    let options = libxch::Options::new();
+    options.xch_with(&hardened, dir.join("shared/file1"), dir.join("shared/file2")).expect("Could not exchange");
+    assert!(util::ensure_file_content(dir.join("shared/file1"), b"content2").expect("Could not read file"));
+    let err = options.xch_with(&hardened, dir.join("link/file1"), dir.join("link/file2")).expect_err("symlinked parent was followed");
+    assert_eq!(err.kind(), libxch::ErrorKind::Refused);
+    let rooted = libxch::Rooted::open(&dir).expect("Could not open root").no_symlinks(true);
+    assert_eq!(options.xch_with(&rooted, "/link/file1", "/link/file2").unwrap_err().kind(), libxch::ErrorKind::Refused);
+
+    // Replace the parent directory with a symbolic link to the outside, over and over again,
+    // while exchanging paths in it. The exchanges must never reach the outside directory.
+    let stop = Arc::new(AtomicBool::new(false));
+    let flipper = {
+        let (stop, dir) = (stop.clone(), dir.clone());
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                libxch::xch(dir.join("shared"), dir.join("link")).expect("Could not flip directory and symlink");
+            }
+        })
+    };
+    for _ in 0..500 {
+        let _ = options.xch_with(&hardened, dir.join("shared/file1"), dir.join("shared/file2"));
+    }
+    stop.store(true, Ordering::Relaxed);
+    flipper.join().expect("Flipping thread panicked");
+    assert!(util::ensure_file_content(dir.join("outside/file1"), b"content1").expect("Could not read file"));
+    assert!(util::ensure_file_content(dir.join("outside/file2"), b"content2").expect("Could not read file"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_secure() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let shared = dir.path().join("shared");
+    fs::create_dir(&shared).expect("Could not create directory in tempdir");
+    let file1 = shared.join("file1");
+    let file2 = shared.join("file2");
+    util::create_file_with_content(&file1, b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(&file2, b"content2").expect("Could not create file in tempdir");
+    let chmod = |path: &std::path::Path, mode| fs::set_permissions(path, fs::Permissions::from_mode(mode)).expect("Could not change permissions");
+    chmod(&shared, 0o755);
+    chmod(&file1, 0o644);
+    chmod(&file2, 0o644);
+
+    let options = libxch::Options::new().secure(true);
+    assert!(options.xch(&file1, &file2).is_ok());
+
+    chmod(&shared, 0o777);
+    let err = options.xch(&file1, &file2).expect_err("world writable directory was accepted");
+    assert!(matches!(err, libxch::Error::Insecure { .. }), "{:?}", err);
+    assert_eq!(err.kind(), libxch::ErrorKind::Refused);
+    assert!(util::ensure_file_content(&file1, b"content2").expect("Could not read file"));
+
+    chmod(&shared, 0o1777);
+    assert!(options.xch(&file1, &file2).is_ok());
+
+    chmod(&file2, 0o664);
+    assert!(matches!(options.xch(&file1, &file2), Err(libxch::Error::Insecure { .. })));
+    assert!(libxch::Options::new().xch(&file1, &file2).is_ok());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_rooted_secure() {
+    use libxch::Backend;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let shared = dir.path().join("shared");
+    fs::create_dir(&shared).expect("Could not create directory in tempdir");
+    util::create_file_with_content(shared.join("file1"), b"content1").expect("Could not create file in tempdir");
+    util::create_file_with_content(shared.join("file2"), b"content2").expect("Could not create file in tempdir");
+    fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).expect("Could not change permissions");
+
+    // The exchange itself checks the directories it opened, without the checks of `Options`.
+    let rooted = libxch::Rooted::open(dir.path()).expect("Could not open root").secure(true);
+    assert!(rooted.exchange("/shared/file1".as_ref(), "/shared/file2".as_ref()).is_ok());
+    fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).expect("Could not change permissions");
+    let err = rooted.exchange("/shared/file1".as_ref(), "/shared/file2".as_ref()).expect_err("world writable directory was accepted");
+    assert!(matches!(err, libxch::Error::Insecure { .. }), "{:?}", err);
+    assert!(util::ensure_file_content(shared.join("file1"), b"content2").expect("Could not read file"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_preserve_labels() {
+    let dir = tempdir::TempDir::new("test").expect("Could not create temporary directory");
+    let live = dir.path().join("live");
+    let next = dir.path().join("next");
+    std::fs::create_dir(&live).expect("Could not create directory in tempdir");
+    std::fs::create_dir(&next).expect("Could not create directory in tempdir");
+    util::create_file_with_content(next.join("index.html"), b"new").expect("Could not create file in tempdir");
+
+    // An access ACL granting read access to user 4242, in the format of system.posix_acl_access.
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for &(tag, perm, id) in &[(0x01u16, 7u16, u32::MAX), (0x02, 5, 4242), (0x04, 5, u32::MAX), (0x10, 5, u32::MAX), (0x20, 5, u32::MAX)] {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    libxch::set_label(&live, "system.posix_acl_access", Some(&acl)).expect("Could not set ACL");
+    let labels = libxch::labels(&live).expect("Could not read labels");
+    assert_eq!(labels.get("system.posix_acl_access"), Some(&acl[..]));
+
+    assert!(libxch::Options::new().preserve_labels(true).xch(&live, &next).is_ok());
+    assert!(util::ensure_file_content(live.join("index.html"), b"new").expect("Could not read file"));
+    assert_eq!(libxch::labels(&live).expect("Could not read labels"), labels);
+    assert_eq!(libxch::labels(&next).expect("Could not read labels").get("system.posix_acl_access"), None);
+
+    assert!(libxch::xch(&live, &next).is_ok());
+    assert_eq!(libxch::labels(&next).expect("Could not read labels"), labels);
+}